@@ -0,0 +1,29 @@
+use std::collections::HashMap;
+use serde::{Serialize, Deserialize};
+use super::state::StateFile;
+use crate::fs::state::Identity;
+
+/// Identities of the files as they were the last time they were synced from this machine.
+/// Comparing both sides against it tells which one changed since then.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct SyncBase {
+    files: HashMap<String, Identity>
+}
+
+impl SyncBase {
+    pub fn get(&self, path: &str) -> Option<&Identity> {
+        self.files.get(path)
+    }
+
+    pub fn set(&mut self, path: String, id: Identity) {
+        self.files.insert(path, id);
+    }
+
+    pub fn remove(&mut self, path: &str) {
+        self.files.remove(path);
+    }
+}
+
+impl StateFile for SyncBase {
+    const NAME: &'static str = "base.json";
+}
@@ -0,0 +1,100 @@
+use log::{debug, info, warn};
+use indicatif::ProgressBar;
+use super::BDrive;
+use super::dangling::Dangling;
+use crate::fs::state::Identity;
+use crate::storage::{blob_key, StorageError};
+use crate::db::{Chunk, DatabaseError};
+
+impl BDrive {
+    /// Make sure the content `id` of the local file `rel` is in the storage and count a reference
+    /// to each of its chunks. Chunks already stored aren't transferred again. The transfer runs
+    /// on a thread of its own and adds its progress to `progress`, if given.
+    pub(crate) async fn store_content(&self, rel: &str, id: &Identity, progress: Option<ProgressBar>) -> Result<Vec<Chunk>, BlobError> {
+        let (rel, id) = (rel.to_string(), id.clone());
+        let (chunks, _pins) = self.off_runtime(move |bd| bd.send(&rel, &id, progress.as_ref())).await?;
+        self.ref_content(&chunks).await?;
+        Ok(chunks)
+    }
+
+    /// Count a reference to each of `chunks`. If one fails the references already added are
+    /// dropped again.
+    pub(crate) async fn ref_content(&self, chunks: &[Chunk]) -> Result<(), DatabaseError> {
+        for (i, chunk) in chunks.iter().enumerate() {
+            match self.db.ref_blob(chunk.blob(), chunk.stored_size()).await {
+                Ok(refs) => debug!("blob {} has {} references", chunk.blob(), refs),
+                Err(e) => {
+                    if let Err(e) = self.release_content(&chunks[..i]).await {
+                        warn!("cannot release chunks: {:?}", e);
+                    }
+                    return Err(e)
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop a reference to each of `chunks`, all are attempted and the first error is returned.
+    /// Failures are recorded in the dangling journal, for the garbage collector to clean up.
+    pub(crate) async fn release_content(&self, chunks: &[Chunk]) -> Result<(), BlobError> {
+        let mut result = Ok(());
+        let mut dangling = vec![];
+        for chunk in chunks {
+            if let Err(e) = self.release_blob(chunk.blob()).await {
+                warn!("cannot release blob {}: {:?}", chunk.blob(), e);
+                dangling.push(match e {
+                    BlobError::DatabaseError(_) => Dangling::Unref { hash: chunk.blob().to_string() },
+                    BlobError::StorageError(_) => Dangling::Delete { key: blob_key(chunk.blob()) }
+                });
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        if !dangling.is_empty() {
+            self.record_dangling(dangling);
+        }
+        result
+    }
+
+    /// Drop a reference to the blob `hash`, deleting it from the storage when it was the last
+    /// one, unless an upload here is about to reference it again.
+    async fn release_blob(&self, hash: &str) -> Result<(), BlobError> {
+        let refs = self.db.unref_blob(hash).await?;
+        debug!("blob {} has {} references", hash, refs);
+        if refs == 0 {
+            let hash = hash.to_string();
+            self.off_runtime(move |bd| {
+                let _claim = bd.in_flight.claim(&hash);
+                if bd.in_flight.is_pinned(&hash) {
+                    info!("unused blob {} is being uploaded again, keeping it", hash);
+                    return Ok(())
+                }
+                info!("deleting unused blob {}", hash);
+                match bd.storage.delete(&blob_key(&hash)) {
+                    Ok(()) | Err(StorageError::NotFound(_)) => Ok(()),
+                    Err(e) => Err(e)
+                }
+            }).await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum BlobError {
+    StorageError(StorageError),
+    DatabaseError(DatabaseError)
+}
+
+impl From<StorageError> for BlobError {
+    fn from(value: StorageError) -> Self {
+        Self::StorageError(value)
+    }
+}
+
+impl From<DatabaseError> for BlobError {
+    fn from(value: DatabaseError) -> Self {
+        Self::DatabaseError(value)
+    }
+}
@@ -0,0 +1,56 @@
+use hex::ToHex;
+use log::debug;
+use ring::digest::{digest, SHA256};
+use super::BDrive;
+use crate::compress;
+use crate::crypto::CryptoError;
+use crate::db::Chunk;
+use crate::storage::StorageError;
+
+impl BDrive {
+    /// Whether the content of `rel` gets compressed: compression must be enabled and the file
+    /// must not be in a format that's already compressed.
+    pub(crate) fn compresses(&self, rel: &str) -> bool {
+        self.compression.as_ref().is_some_and(|c| compress::compressible(rel, &c.skip))
+    }
+
+    /// Turn the plaintext `data` of `chunk` into what is stored, filling in how it was done.
+    /// The chunk is compressed first, if `compress` and it actually gets smaller, then encrypted.
+    /// Without compression nor encryption nothing changes.
+    pub(crate) fn encode(&self, mut chunk: Chunk, mut data: Vec<u8>, compress: bool) -> (Chunk, Vec<u8>) {
+        if let Some(c) = self.compression.as_ref().filter(|_| compress) {
+            match c.codec.compress(&data, c.level) {
+                Ok(compressed) if compressed.len() < data.len() => {
+                    chunk.codec = Some(c.codec);
+                    data = compressed;
+                }
+                Ok(_) => debug!("chunk {} doesn't compress, storing it as it is", chunk.hash),
+                Err(e) => debug!("cannot compress chunk {}, storing it as it is: {}", chunk.hash, e)
+            }
+        }
+        if let Some(cipher) = &self.cipher {
+            let (nonce, sealed) = cipher.seal(&chunk.hash, data);
+            chunk.nonce = Some(nonce);
+            data = sealed;
+        }
+        if !chunk.is_plain() {
+            chunk.blob = Some(digest(&SHA256, &data).encode_hex());
+            chunk.stored = Some(data.len() as u64);
+        }
+        (chunk, data)
+    }
+
+    /// Get back the plaintext of `chunk` from the stored `data`.
+    pub(crate) fn decode(&self, chunk: &Chunk, mut data: Vec<u8>) -> Result<Vec<u8>, StorageError> {
+        if let Some(nonce) = &chunk.nonce {
+            data = match &self.cipher {
+                Some(cipher) => cipher.open(&chunk.hash, nonce, data)?,
+                None => return Err(CryptoError::NoKey(chunk.hash.clone()).into())
+            };
+        }
+        if let Some(codec) = &chunk.codec {
+            data = codec.decompress(&data, chunk.size)?;
+        }
+        Ok(data)
+    }
+}
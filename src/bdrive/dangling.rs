@@ -0,0 +1,51 @@
+use log::warn;
+use serde::{Serialize, Deserialize};
+use super::BDrive;
+use super::state::StateFile;
+
+/// Something left behind by an operation that couldn't clean up after itself.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum Dangling {
+    /// A reference to the blob `hash` that couldn't be dropped.
+    Unref { hash: String },
+    /// A storage key that couldn't be deleted.
+    Delete { key: String }
+}
+
+/// Dangling entries waiting for the garbage collector. It's kept in the local state directory:
+/// cleanups usually fail because the database or the storage can't be reached, so neither is a
+/// good place to record them.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct DanglingJournal {
+    entries: Vec<Dangling>
+}
+
+impl DanglingJournal {
+    pub fn entries(&self) -> &[Dangling] {
+        &self.entries
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+impl StateFile for DanglingJournal {
+    const NAME: &'static str = "dangling.json";
+}
+
+impl BDrive {
+    /// Add `entries` to the dangling journal. This runs while handling another error, so a
+    /// failure here is only logged.
+    pub(crate) fn record_dangling(&self, entries: impl IntoIterator<Item = Dangling>) {
+        let _lock = self.state_lock.lock().unwrap();
+        let result = self.load_state::<DanglingJournal>().and_then(|mut journal| {
+            journal.entries.extend(entries);
+            self.save_state(&journal)
+        });
+        if let Err((p, e)) = result {
+            warn!("cannot record dangling entries in {}: {:?}", p, e);
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use super::{BDrive, BlobError, SyncBase};
+use crate::conf::PathError;
+use crate::fs::{File, state::*, LocalFile, Upload};
+use crate::storage::StorageError;
+use log::{info, warn};
+use crate::db::{DatabaseError, Tombstone};
+
+impl BDrive {
+    /// This function removes a file from the remote storage.
+    /// Unless the trash is disabled the file goes there first, with its own references to the
+    /// content, and can be restored until it's purged; its versions stay meanwhile. Then the
+    /// database record is removed, then its references to the content: each blob is deleted
+    /// once nothing uses it. If that fails the blob is left dangling on the server, but the
+    /// database never describes a file that doesn't exist. A tombstone records who deleted the
+    /// file and what it contained, for the machines that still have a copy. Expired trash is left
+    /// for the caller to purge once it's done, see [`BDrive::purge_expired`].
+    pub async fn delete(&mut self, path: &str) -> Result<File<Remote>, DeleteError> {
+        self.delete_remote(path, true).await
+    }
+
+    async fn delete_remote(&mut self, path: &str, tombstone: bool) -> Result<File<Remote>, DeleteError> {
+        let path = self.canonicalize(path);
+
+        let remote = match self.db.get_file_path(&path).await {
+            Ok(Some(r)) => r,
+            Ok(None) => return Err(DeleteError::NotFound(path)),
+            Err(e) => return Err(DeleteError::DatabaseError(path, e))
+        };
+        let chunks = match self.chunks(&path).await {
+            Ok(c) => c,
+            Err(e) => return Err(DeleteError::DatabaseError(path, e))
+        };
+
+        let trashed = match self.trash.days {
+            0 => None,
+            _ => match self.add_trash(&remote, &chunks).await {
+                Ok(t) => Some(t),
+                Err(e) => return Err(DeleteError::DatabaseError(path, e))
+            }
+        };
+
+        info!("deleting {} from database", path);
+        if let Err(e) = self.db.delete(&path).await {
+            if let Some(t) = trashed {
+                if let Err(e) = self.drop_trashed(&t).await {
+                    warn!("cannot remove {} from the trash: {:?}", path, e);
+                }
+            }
+            return Err(DeleteError::DatabaseError(path, e))
+        }
+
+        if tombstone {
+            self.record_tombstone(&remote).await;
+        }
+
+        info!("releasing content of {}", path);
+        let released = match self.release_content(&chunks).await {
+            Ok(()) if trashed.is_none() => self.forget_versions(&path).await,
+            r => r
+        };
+        match released {
+            Ok(()) => Ok(remote),
+            Err(BlobError::StorageError(e)) => Err(DeleteError::StorageError(remote, e)),
+            Err(BlobError::DatabaseError(e)) => Err(DeleteError::DatabaseError(path, e))
+        }
+    }
+
+    /// Record the deletion of `remote`, replacing an older tombstone of its path. The file is
+    /// gone either way, so a failure is only logged.
+    async fn record_tombstone(&self, remote: &File<Remote>) {
+        let id = remote.remote_identity();
+        let tombstone = Tombstone {
+            path: remote.path.clone(),
+            hash: id.hash(),
+            size: id.size(),
+            deleted: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+            device: self.device.clone()
+        };
+        if let Err(e) = self.db.set_tombstone(tombstone).await {
+            warn!("cannot record the deletion of {}: {:?}", remote.path, e);
+        }
+    }
+
+    /// Delete a file from the remote and, unless `keep_local`, from the local tree too.
+    /// The file is forgotten by the sync base and leaves no tombstone, so a kept local copy is
+    /// considered new. A local copy with changes that were never uploaded would be lost for good,
+    /// the trash only keeps the remote content: then nothing is deleted unless `force`.
+    pub async fn remove(&mut self, path: &str, keep_local: bool, force: bool) -> Result<File<Remote>, DeleteError> {
+        if !keep_local && !force {
+            let canonical = self.canonicalize(path);
+            let remote = match self.db.get_file_path(&canonical).await {
+                Ok(Some(r)) => r,
+                Ok(None) => return Err(DeleteError::NotFound(canonical)),
+                Err(e) => return Err(DeleteError::DatabaseError(canonical, e))
+            };
+            let local = self.paths.to_local(&canonical).map_err(DeleteError::PathError)?;
+            if local.is_file() {
+                let local = File::from(local).hash().map_err(|(e, _)| DeleteError::IOError(canonical.clone(), e))?;
+                if local.local_identity() != remote.remote_identity() {
+                    return Err(DeleteError::Modified(canonical))
+                }
+            }
+        }
+        let remote = self.delete_remote(path, !keep_local).await?;
+
+        let mut base = self.load_state::<SyncBase>().map_err(|(p, e)| DeleteError::IOError(p, e))?;
+        base.remove(&remote.path);
+        self.save_state(&base).map_err(|(p, e)| DeleteError::IOError(p, e))?;
+
+        if !keep_local {
+            let local = self.paths.to_local(&remote.path).map_err(DeleteError::PathError)?;
+            if local.is_file() {
+                info!("deleting {} from local tree", remote.path);
+                std::fs::remove_file(local).map_err(|e| DeleteError::IOError(remote.path(), e))?;
+            }
+        }
+
+        Ok(remote)
+    }
+}
+
+#[derive(Debug)]
+pub enum DeleteError {
+    NotFound(String),
+    /// The local file differs from the remote one, removing it would lose its changes.
+    Modified(String),
+    PathError(PathError),
+    IOError(String, std::io::Error),
+    StorageError(File<Remote>, StorageError),
+    DatabaseError(String, DatabaseError)
+}
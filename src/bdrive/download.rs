@@ -0,0 +1,138 @@
+use std::path::{Path, PathBuf};
+use super::BDrive;
+use crate::conf::PathError;
+use crate::fs::{File, state::*, SyncState, Upload, Split, LocalFile};
+use crate::storage::StorageError;
+use log::{info, warn};
+use crate::db::{Chunk, DatabaseError};
+
+impl BDrive {
+    /// This function downloads a file from the remote storage into the local tree.
+    /// The content is written to a temporary file next to the destination, then hashed and
+    /// compared with the identity stored in the database: only if they match the temporary file
+    /// replaces the local one, otherwise it's removed and an IntegrityError is returned.
+    pub async fn download(&self, path: &str, options: Option<DownloadOptions>) -> Result<File<Sync>, DownloadError> {
+        let options = options.unwrap_or_default();
+        let path = self.canonicalize(path);
+
+        let remote = match self.db.get_file_path(&path).await {
+            Ok(Some(r)) => r,
+            Ok(None) => return Err(DownloadError::NotFound(path)),
+            Err(e) => return Err(DownloadError::DatabaseError(path, e))
+        };
+        let chunks = match self.chunks(&path).await {
+            Ok(c) => c,
+            Err(e) => return Err(DownloadError::DatabaseError(path, e))
+        };
+        let dest = match self.paths.to_local(&path) {
+            Ok(d) => d,
+            Err(e) => return Err(DownloadError::PathError(remote, e))
+        };
+
+        if dest.is_file() {
+            let local = match File::from(dest.clone()).hash() {
+                Ok(l) => File::new(path.clone(), LocalHashed::new(l.local_identity())),
+                Err((e, _)) => return Err(DownloadError::IOError(remote, e))
+            };
+            match local.attach_remote(remote) {
+                SyncState::Sync(f) => {
+                    info!("file is already in sync");
+                    return Ok(f)
+                }
+                SyncState::Diff(d) => if options.overwrite {
+                    info!("overwriting local file");
+                    let (_, r) = d.split();
+                    return self.off_runtime(move |bd| bd.fetch(r, &chunks, &dest)).await
+                } else {
+                    let (local, remote) = d.split();
+                    return Err(DownloadError::OverwriteError(local, remote))
+                }
+            }
+        }
+
+        self.off_runtime(move |bd| bd.fetch(remote, &chunks, &dest)).await
+    }
+
+    /// Download `chunks` into `dest` and check them against `remote`, it blocks until done.
+    pub(crate) fn fetch(&self, remote: File<Remote>, chunks: &[Chunk], dest: &Path) -> Result<File<Sync>, DownloadError> {
+        let tmp = part_path(dest);
+        if let Some(parent) = dest.parent() {
+            if let Err(e) = std::fs::create_dir_all(parent) {
+                return Err(DownloadError::IOError(remote, e))
+            }
+        }
+
+        if let Err(e) = self.receive(chunks, &tmp, remote.remote_identity().size()) {
+            let _ = std::fs::remove_file(&tmp);
+            return Err(DownloadError::StorageError(remote, e))
+        }
+
+        let local = match File::from(tmp.clone()).hash() {
+            Ok(l) => File::new(remote.path(), LocalHashed::new(l.local_identity())),
+            Err((e, _)) => {
+                let _ = std::fs::remove_file(&tmp);
+                return Err(DownloadError::IOError(remote, e))
+            }
+        };
+
+        match local.attach_remote(remote) {
+            SyncState::Sync(f) => match std::fs::rename(&tmp, dest) {
+                Ok(()) => Ok(f),
+                Err(e) => {
+                    let _ = std::fs::remove_file(&tmp);
+                    let (_, r) = f.split();
+                    Err(DownloadError::IOError(r, e))
+                }
+            },
+            SyncState::Diff(d) => {
+                warn!("downloaded content doesn't match database, discarding it");
+                let _ = std::fs::remove_file(&tmp);
+                Err(DownloadError::IntegrityError(d))
+            }
+        }
+    }
+}
+
+/// Temporary path used while a download is in progress.
+fn part_path(dest: &Path) -> PathBuf {
+    let mut name = dest.file_name().unwrap_or_default().to_os_string();
+    name.push(".bdrive-part");
+    dest.with_file_name(name)
+}
+
+#[derive(Clone, Default)]
+pub struct DownloadOptions {
+    pub overwrite: bool
+}
+
+pub struct DownloadOptionsBuilder {
+    inner: DownloadOptions
+}
+
+impl DownloadOptions {
+    pub fn builder() -> DownloadOptionsBuilder {
+        DownloadOptionsBuilder { inner: Self::default() }
+    }
+}
+
+impl DownloadOptionsBuilder {
+    pub fn overwrite(mut self, overwrite: bool) -> DownloadOptionsBuilder {
+        self.inner.overwrite = overwrite;
+        self
+    }
+
+    pub fn build(self) -> DownloadOptions {
+        self.inner
+    }
+}
+
+#[derive(Debug)]
+pub enum DownloadError {
+    NotFound(String),
+    OverwriteError(File<LocalHashed>, File<Remote>),
+    IntegrityError(File<Diff>),
+    PathError(File<Remote>, PathError),
+    StorageError(File<Remote>, StorageError),
+    IOError(File<Remote>, std::io::Error),
+    DatabaseError(String, DatabaseError)
+}
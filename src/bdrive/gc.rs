@@ -0,0 +1,189 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use log::{info, warn};
+use serde::Serialize;
+use super::BDrive;
+use super::dangling::{Dangling, DanglingJournal};
+use crate::db::{Blob, DatabaseError};
+use crate::storage::{blob_key, is_blob_name, StorageError};
+
+/// What the garbage collector found, and removed unless it was a dry run.
+#[derive(Serialize, Debug, Default)]
+pub struct GcReport {
+    /// Stored blobs that no file uses.
+    pub orphans: Vec<String>,
+    /// Leftovers of interrupted writes.
+    pub partials: Vec<String>,
+    /// Blobs whose reference count was wrong: hash, recorded and actual count.
+    pub refs: Vec<(String, i64, i64)>,
+    /// Blobs used by some file but missing from the storage, these can't be fixed here.
+    pub missing: Vec<String>,
+    /// Contents stored at the path of their file by older versions, see [`BDrive::migrate`].
+    pub legacy: Vec<String>,
+    /// Keys not written by bdrive. They're left alone.
+    pub unknown: Vec<String>,
+    /// Entries of the dangling journal that were resolved.
+    pub journal: usize
+}
+
+impl Display for GcReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for k in &self.orphans {
+            writeln!(f, "{:<10} {}", "orphan", k)?;
+        }
+        for k in &self.partials {
+            writeln!(f, "{:<10} {}", "partial", k)?;
+        }
+        for (h, recorded, actual) in &self.refs {
+            writeln!(f, "{:<10} {} ({} recorded, {} actual)", "refs", h, recorded, actual)?;
+        }
+        for h in &self.missing {
+            writeln!(f, "{:<10} {}", "missing", h)?;
+        }
+        for k in &self.legacy {
+            writeln!(f, "{:<10} {}", "legacy", k)?;
+        }
+        for k in &self.unknown {
+            writeln!(f, "{:<10} {}", "unknown", k)?;
+        }
+        Ok(())
+    }
+}
+
+impl BDrive {
+    /// Compare the storage and the blob records with the files, versions, snapshots and trash in
+    /// the database. Stored blobs nothing uses and leftovers of interrupted writes are deleted,
+    /// along with the progress of the uploads that stored them; wrong reference counts are
+    /// corrected and the dangling journal is emptied. With `dry_run` nothing is changed. Files
+    /// stored by older versions are only reported, see [`BDrive::migrate`].
+    /// The records are the only source of truth here, so this must not run while other machines
+    /// are uploading to the same storage.
+    pub async fn gc(&self, dry_run: bool) -> Result<GcReport, GcError> {
+        let mut report = GcReport::default();
+        let mut journal = self.load_state::<DanglingJournal>().map_err(|(p, e)| GcError::IOError(p, e))?;
+
+        // records left by older versions may or may not own a reference to their blob, migrating
+        // them sorts that out: until then their blobs are left alone
+        let (legacy, legacy_blobs): (HashSet<String>, HashSet<String>) = self.db.legacy_records("").await?
+            .into_iter()
+            .map(|r| (r.path, r.hash))
+            .unzip();
+
+        // actual references of every blob
+        let mut used: HashMap<String, (u64, i64)> = HashMap::new();
+        let files = self.db.list_chunks("").await?.into_iter()
+            .filter(|(p, _)| !legacy.contains(p))
+            .map(|(_, c)| c);
+        let versions = self.db.list_versions("").await?.into_iter().map(|v| v.chunks);
+        let snapshots = self.db.snapshots().await?.into_iter()
+            .flat_map(|s| s.files)
+            .map(|f| f.chunks());
+        let trash = self.db.trash("").await?.into_iter().map(|t| t.chunks);
+        for chunks in files.chain(versions).chain(snapshots).chain(trash) {
+            for chunk in chunks {
+                used.entry(chunk.blob().to_string()).or_insert((chunk.stored_size(), 0)).1 += 1;
+            }
+        }
+
+        let recorded: HashMap<String, Blob> = self.db.blobs().await?.into_iter()
+            .map(|b| (b.hash.clone(), b))
+            .collect();
+        let hashes: HashSet<&String> = used.keys().chain(recorded.keys()).collect();
+        for hash in hashes.into_iter().filter(|h| !legacy_blobs.contains(*h)) {
+            let actual = used.get(hash).map(|u| u.1).unwrap_or(0);
+            let (size, refs) = match recorded.get(hash) {
+                Some(b) => (b.size, b.refs),
+                None => (used[hash].0, 0)
+            };
+            if refs != actual {
+                report.refs.push((hash.clone(), refs, actual));
+                if !dry_run {
+                    self.db.set_blob(Blob { hash: hash.clone(), size, refs: actual }).await?;
+                }
+            }
+        }
+
+        let mut stored = HashSet::new();
+        for key in self.on_storage(|s| s.list("")).await? {
+            let name = key.rsplit('/').next().unwrap_or_default().to_string();
+            if key.starts_with("partial/") {
+                report.partials.push(key);
+            } else if key.starts_with("blobs/") && is_blob_name(&name) && key == blob_key(&name) {
+                if !used.contains_key(&name) && !legacy_blobs.contains(&name) {
+                    report.orphans.push(key);
+                }
+                stored.insert(name);
+            } else if legacy.contains(&key) {
+                report.legacy.push(key);
+            } else {
+                report.unknown.push(key);
+            }
+        }
+        report.missing = used.keys().filter(|h| !stored.contains(*h)).cloned().collect();
+
+        if !dry_run {
+            let keys: Vec<String> = report.orphans.iter().chain(report.partials.iter()).cloned().collect();
+            self.on_storage(move |s| {
+                for key in keys {
+                    info!("deleting {}", key);
+                    match s.delete(&key) {
+                        Ok(()) | Err(StorageError::NotFound(_)) => {},
+                        Err(e) => return Err(e)
+                    }
+                }
+                Ok(())
+            }).await?;
+
+            // interrupted uploads that stored these must not count on them any more
+            let removed: HashSet<String> = report.orphans.iter().chain(report.partials.iter())
+                .map(|k| k.rsplit('/').next().unwrap_or_default().to_string())
+                .collect();
+            let forgotten = self.update_journal(|j| j.forget(&removed));
+            if forgotten > 0 {
+                info!("forgot the progress of {} interrupted uploads", forgotten);
+            }
+        }
+
+        // everything the journal points to is covered by the comparison above
+        for entry in journal.entries() {
+            match entry {
+                Dangling::Unref { hash } => info!("dangling reference to {} resolved", hash),
+                Dangling::Delete { key } => if report.unknown.contains(key) {
+                    warn!("dangling key {} isn't a blob, left alone", key);
+                }
+            }
+        }
+        report.journal = journal.entries().len();
+        if !dry_run {
+            journal.clear();
+            self.save_state(&journal).map_err(|(p, e)| GcError::IOError(p, e))?;
+        }
+
+        report.orphans.sort();
+        report.partials.sort();
+        report.refs.sort();
+        report.missing.sort();
+        report.legacy.sort();
+        report.unknown.sort();
+        Ok(report)
+    }
+}
+
+#[derive(Debug)]
+pub enum GcError {
+    IOError(String, std::io::Error),
+    StorageError(StorageError),
+    DatabaseError(DatabaseError)
+}
+
+impl From<StorageError> for GcError {
+    fn from(value: StorageError) -> Self {
+        Self::StorageError(value)
+    }
+}
+
+impl From<DatabaseError> for GcError {
+    fn from(value: DatabaseError) -> Self {
+        Self::DatabaseError(value)
+    }
+}
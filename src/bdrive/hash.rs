@@ -1,7 +1,67 @@
+use std::io::Write;
+use hex::ToHex;
 use super::BDrive;
+use crate::db::{Chunk, DatabaseError};
+use crate::fs::HashWriter;
+use crate::fs::state::Identity;
+use crate::storage::{blob_key, StorageError};
 
 impl BDrive {
-    // pub fn remote_hash(&self,) {
-    //
-    // }
+    /// Identity of the remote file `path` as it's actually stored, rather than as the database
+    /// describes it. The storage hashes it on its side when it can, so usually nothing is
+    /// downloaded, but encrypted contents must be decrypted here. `None` if the file or one of
+    /// its chunks is missing.
+    pub async fn remote_hash(&self, path: &str) -> Result<Option<Identity>, RemoteHashError> {
+        let path = self.canonicalize(path);
+        let chunks = self.chunks(&path).await.map_err(RemoteHashError::DatabaseError)?;
+        if chunks.is_empty() && !self.db.exists(&path).await.map_err(RemoteHashError::DatabaseError)? {
+            return Ok(None)
+        }
+        self.off_runtime(move |bd| bd.stored_identity(&chunks)).await.map_err(RemoteHashError::StorageError)
+    }
+
+    /// Identity of the content made of the stored `chunks`, it blocks until the storage answers.
+    fn stored_identity(&self, chunks: &[Chunk]) -> Result<Option<Identity>, StorageError> {
+        if !chunks.iter().all(|c| c.is_plain()) {
+            return self.decoded_hash(chunks)
+        }
+        let mut size = 0;
+        for chunk in chunks {
+            match self.storage.stat(&blob_key(chunk.blob()))? {
+                Some(s) => size += s,
+                None => return Ok(None)
+            }
+        }
+        Ok(self.content_hash(chunks)?.map(|h| Identity::new(h, size)))
+    }
+
+    /// SHA-256 of `chunks` joined, as they're stored.
+    pub(crate) fn content_hash(&self, chunks: &[Chunk]) -> Result<Option<String>, StorageError> {
+        let keys: Vec<String> = chunks.iter().map(|c| blob_key(c.blob())).collect();
+        self.storage.hash_all(&keys)
+    }
+
+    /// Identity of the plaintext of `chunks`, which have to be read back and decoded here.
+    fn decoded_hash(&self, chunks: &[Chunk]) -> Result<Option<Identity>, StorageError> {
+        let mut hasher = HashWriter::new();
+        let mut size = 0;
+        for chunk in chunks {
+            let mut data = Vec::with_capacity(chunk.stored_size() as usize);
+            match self.storage.get(&blob_key(chunk.blob()), &mut data) {
+                Ok(_) => {},
+                Err(StorageError::NotFound(_)) => return Ok(None),
+                Err(e) => return Err(e)
+            }
+            let data = self.decode(chunk, data)?;
+            size += data.len() as u64;
+            hasher.write_all(&data)?;
+        }
+        Ok(Some(Identity::new(hasher.finish().encode_hex(), size)))
+    }
+}
+
+#[derive(Debug)]
+pub enum RemoteHashError {
+    StorageError(StorageError),
+    DatabaseError(DatabaseError)
 }
@@ -0,0 +1,72 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use log::warn;
+use super::BDrive;
+
+/// Name of the files holding gitignore-style patterns, valid for their directory and below.
+pub const IGNORE_FILE: &str = ".bdriveignore";
+
+/// Patterns that are always ignored.
+const BUILTIN: &[&str] = &["*.bdrive-part"];
+
+/// Decides whether a path is excluded from syncing. Deeper ignore files take precedence over
+/// the ones closer to the root, and the global patterns from the configuration come last.
+pub(crate) struct IgnoreRules {
+    root: PathBuf,
+    global: Gitignore,
+    // ignore files are parsed the first time their directory is looked at
+    files: RefCell<HashMap<PathBuf, Gitignore>>
+}
+
+impl IgnoreRules {
+    pub fn new(root: &str, patterns: &[String]) -> Self {
+        let mut builder = GitignoreBuilder::new(root);
+        for p in BUILTIN.iter().copied().chain(patterns.iter().map(|p| p.as_str())) {
+            if let Err(e) = builder.add_line(None, p) {
+                warn!("invalid ignore pattern {:?}: {}", p, e);
+            }
+        }
+        Self {
+            root: PathBuf::from(root),
+            global: builder.build().unwrap_or_else(|_| Gitignore::empty()),
+            files: RefCell::new(HashMap::new())
+        }
+    }
+
+    /// Whether `path`, relative to the local root, is ignored.
+    pub fn is_ignored(&self, path: &str, is_dir: bool) -> bool {
+        let path = self.root.join(path);
+        let mut dir = path.parent();
+        while let Some(d) = dir.filter(|d| d.starts_with(&self.root)) {
+            let mut files = self.files.borrow_mut();
+            let rules = files.entry(d.to_path_buf()).or_insert_with(|| load(d));
+            match rules.matched_path_or_any_parents(&path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => dir = d.parent()
+            }
+        }
+        self.global.matched_path_or_any_parents(&path, is_dir).is_ignore()
+    }
+}
+
+fn load(dir: &Path) -> Gitignore {
+    let file = dir.join(IGNORE_FILE);
+    if !file.is_file() {
+        return Gitignore::empty()
+    }
+    let mut builder = GitignoreBuilder::new(dir);
+    if let Some(e) = builder.add(&file) {
+        warn!("cannot fully parse {:?}: {}", file, e);
+    }
+    builder.build().unwrap_or_else(|_| Gitignore::empty())
+}
+
+impl BDrive {
+    pub(crate) fn ignore_rules(&self) -> IgnoreRules {
+        IgnoreRules::new(&self.paths.local, &self.paths.ignore)
+    }
+}
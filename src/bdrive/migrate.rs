@@ -0,0 +1,154 @@
+use std::fmt::{Display, Formatter};
+use log::{info, warn};
+use serde::Serialize;
+use super::BDrive;
+use crate::db::{Chunk, DatabaseError, RemoteFile};
+use crate::storage::{blob_key, StorageError};
+
+/// What moving the contents left at file paths by older versions of bdrive changed, or would
+/// change on a dry run.
+#[derive(Serialize, Debug, Default)]
+pub struct MigrateReport {
+    /// Files whose content was moved from their path to their blob.
+    pub moved: Vec<String>,
+    /// Files whose blob was already stored, the copy at their path was deleted.
+    pub merged: Vec<String>,
+    /// Files already stored as a blob, only their record was completed.
+    pub recorded: Vec<String>,
+    /// Files with their content neither at their path nor in a blob, left as they are.
+    pub missing: Vec<String>
+}
+
+impl Display for MigrateReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for p in &self.moved {
+            writeln!(f, "{:<10} {}", "moved", p)?;
+        }
+        for p in &self.merged {
+            writeln!(f, "{:<10} {}", "merged", p)?;
+        }
+        for p in &self.recorded {
+            writeln!(f, "{:<10} {}", "recorded", p)?;
+        }
+        for p in &self.missing {
+            writeln!(f, "{:<10} {}", "missing", p)?;
+        }
+        Ok(())
+    }
+}
+
+/// What migrating one record does.
+enum Migration {
+    Moved,
+    Merged,
+    Recorded,
+    Missing
+}
+
+impl BDrive {
+    /// Move the contents older versions of bdrive stored at the path of their file to the blob
+    /// named after their hash, count the reference and record the file chunk, like every other
+    /// upload. With `dry_run` nothing is changed.
+    /// Files are otherwise migrated one by one the first time their content is needed.
+    pub async fn migrate(&self, dry_run: bool) -> Result<MigrateReport, MigrateError> {
+        let mut report = MigrateReport::default();
+        for r in self.db.legacy_records("").await? {
+            let path = r.path.clone();
+            match self.migrate_record(r, dry_run).await? {
+                Migration::Moved => report.moved.push(path),
+                Migration::Merged => report.merged.push(path),
+                Migration::Recorded => report.recorded.push(path),
+                Migration::Missing => report.missing.push(path)
+            }
+        }
+        info!(
+            "{} files moved, {} merged, {} recorded and {} missing",
+            report.moved.len(), report.merged.len(), report.recorded.len(), report.missing.len()
+        );
+        Ok(report)
+    }
+
+    /// Chunks of the remote file `path`, none if there's no such file. A file stored by an older
+    /// version of bdrive is migrated first; when that fails it's only logged, and its content is
+    /// looked for in its blob like before.
+    pub(crate) async fn chunks(&self, path: &str) -> Result<Vec<Chunk>, DatabaseError> {
+        let record = match self.db.record(path).await? {
+            Some(r) => r,
+            None => return Ok(vec![])
+        };
+        let chunks = record.chunks();
+        if record.chunks.is_none() {
+            if let Err(e) = self.migrate_record(record, false).await {
+                warn!("cannot migrate {}: {:?}", path, e);
+            }
+        }
+        Ok(chunks)
+    }
+
+    /// Migrate the legacy records inside directory `dir`, logging failures.
+    pub(crate) async fn migrate_dir(&self, dir: &str) -> Result<(), DatabaseError> {
+        for r in self.db.legacy_records(dir).await? {
+            let path = r.path.clone();
+            if let Err(e) = self.migrate_record(r, false).await {
+                warn!("cannot migrate {}: {:?}", path, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Records without chunks are ambiguous: older versions stored the content at the path of
+    /// the file without a blob record, while early blob versions already stored and counted a
+    /// blob. The storage tells them apart.
+    async fn migrate_record(&self, record: RemoteFile, dry_run: bool) -> Result<Migration, MigrateError> {
+        let old = record.path.clone();
+        let blob = blob_key(&record.hash);
+        let (at_path, in_blob) = {
+            let (old, blob) = (old.clone(), blob.clone());
+            self.on_storage(move |s| Ok::<_, StorageError>((s.stat(&old)?, s.stat(&blob)?))).await?
+        };
+        let size = Some(record.size);
+        let migration = match (at_path == size, in_blob == size) {
+            (true, false) => Migration::Moved,
+            (true, true) => Migration::Merged,
+            (false, true) => Migration::Recorded,
+            (false, false) => return Ok(Migration::Missing)
+        };
+        if dry_run {
+            return Ok(migration)
+        }
+
+        if let Migration::Moved = migration {
+            info!("moving {} to {}", old, blob);
+            let (from, to) = (old.clone(), blob.clone());
+            self.on_storage(move |s| s.rename(&from, &to)).await?;
+        }
+        if !matches!(migration, Migration::Recorded) {
+            self.db.ref_blob(&record.hash, record.size).await?;
+        }
+        let chunks = vec![Chunk::plain(record.hash.clone(), record.size)];
+        self.db.replace_remote(&record.to_local(), chunks).await?;
+        if let Migration::Merged = migration {
+            info!("deleting {}, already stored as {}", old, blob);
+            self.on_storage(move |s| s.delete(&old)).await?;
+        }
+        Ok(migration)
+    }
+}
+
+#[derive(Debug)]
+pub enum MigrateError {
+    StorageError(StorageError),
+    DatabaseError(DatabaseError)
+}
+
+impl From<StorageError> for MigrateError {
+    fn from(value: StorageError) -> Self {
+        MigrateError::StorageError(value)
+    }
+}
+
+impl From<DatabaseError> for MigrateError {
+    fn from(value: DatabaseError) -> Self {
+        MigrateError::DatabaseError(value)
+    }
+}
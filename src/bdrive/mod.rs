@@ -1,47 +1,201 @@
 mod upload;
+mod download;
+mod delete;
+mod rename;
+mod verify;
+mod status;
 mod hash;
 mod paths;
+mod ignores;
+mod base;
+mod sync;
+mod transfer;
+mod blobs;
+mod resume;
+mod dangling;
+mod state;
+mod gc;
+mod codec;
+mod versions;
+mod snapshots;
+mod prune;
+mod trash;
+mod migrate;
 
 pub use upload::{UploadOptions, UploadError};
+pub use download::{DownloadOptions, DownloadError};
+pub use delete::DeleteError;
+pub use rename::RenameError;
+pub use verify::{Verification, VerifyOptions, VerifyError};
+pub use hash::RemoteHashError;
+pub use status::{FileStatus, StatusEntry, StatusReport, StatusError};
+pub use paths::PathSpecial;
+pub use ignores::IGNORE_FILE;
+pub use base::SyncBase;
+pub use sync::{SyncClass, SyncAction, SyncPlan, SyncReport, SyncError};
+pub use blobs::BlobError;
+pub use dangling::{Dangling, DanglingJournal};
+pub use gc::{GcReport, GcError};
+pub use versions::VersionError;
+pub use snapshots::{SnapshotRestore, SnapshotError};
+pub use prune::{PruneReport, PruneError};
+pub use trash::TrashError;
+pub use migrate::{MigrateReport, MigrateError};
 
 use std::future::join;
 use std::path::PathBuf;
-use crate::conf::{Configs, PathsConf};
-use crate::db::Database;
-use crate::ssh::SSHClient;
+use std::sync::{Arc, Mutex};
+use crate::conf::{Backend, CompressionConfig, Configs, IndexBackend, PathsConf, RetentionConfig, TrashConfig};
+use crate::db::{Database, DatabaseError, LocalIndex};
+use crate::fs::File;
+use crate::fs::state::Remote;
+use crate::crypto::Cipher;
+use crate::storage::{LocalStorage, Storage};
+use transfer::InFlight;
 
-#[derive(Debug)]
+/// Directory inside the local root where bdrive keeps its own state, it's never synced.
+pub const STATE_DIR: &str = ".bdrive";
+
+/// Cheap to clone: clones share the connections, so a copy can be moved into a transfer
+/// running on another thread.
+#[derive(Debug, Clone)]
 pub struct BDrive {
-    db: Database,
+    db: Arc<Database>,
+    storage: Arc<dyn Storage>,
+    /// Encrypts contents, if enabled in the configuration.
+    cipher: Option<Arc<Cipher>>,
+    /// Compresses contents, if enabled in the configuration.
+    compression: Option<CompressionConfig>,
+    /// Files hashed and transferred at the same time.
+    jobs: usize,
+    /// Held while updating the journals in the state directory, transfers write them concurrently.
+    state_lock: Arc<Mutex<()>>,
+    in_flight: Arc<InFlight>,
+    /// Name of this machine, recorded in the versions it uploads.
+    device: String,
+    /// What `prune` keeps, if configured.
+    retention: Option<RetentionConfig>,
+    trash: TrashConfig,
     // todo: remove these pub(s)
-    pub ssh: SSHClient,
     pub paths: PathsConf,
     exe_path: PathBuf
 }
 
 impl BDrive {
-    pub async fn new(cfg: Configs) -> mongodb::error::Result<Self> {
+    pub async fn new(cfg: Configs) -> Result<Self, InitError> {
         let curdir = std::env::current_dir()?.canonicalize()?;
         if !curdir.starts_with(&cfg.paths.local) {
-            // todo: fix this
-            panic!("out of bounds: {:?}", curdir);
+            return Err(InitError::Outbound(curdir))
         }
         // assume prefix is present
         let curdir = curdir.strip_prefix(&cfg.paths.local).unwrap();
-        // todo: fix this
-        std::env::set_current_dir(&cfg.paths.local).unwrap();
-        let t_db = cfg.mongodb.to_db();
-        let t_ssh = cfg.ssh.connect();
+        std::env::set_current_dir(&cfg.paths.local)?;
+
+        let cipher = match &cfg.encryption {
+            Some(e) => e.to_cipher().map_err(InitError::Config)?.map(Arc::new),
+            None => None
+        };
+        let encrypt_paths = cfg.encryption.as_ref().is_some_and(|e| e.encrypt_paths);
 
-        let (db, ssh) = join!(t_db, t_ssh).await;
+        let index_path: PathBuf = [cfg.paths.local.as_str(), STATE_DIR, "index.json"].iter().collect();
+        let t_db = async move {
+            match cfg.index {
+                IndexBackend::Mongodb => match cfg.mongodb {
+                    Some(mongodb) => Ok(mongodb.to_db().await?),
+                    None => Err(InitError::Config("the mongodb index needs a [mongodb] section".to_string()))
+                },
+                IndexBackend::Local => Ok(Database::new(LocalIndex::open(index_path)?))
+            }
+        };
+        let root = cfg.paths.remote.clone();
+        let t_storage = async move {
+            Ok::<Arc<dyn Storage>, InitError>(match cfg.backend {
+                Backend::Ssh => match cfg.ssh {
+                    Some(ssh) => Arc::new(ssh.connect(root).await?),
+                    None => return Err(InitError::Config("the ssh backend needs an [ssh] section".to_string()))
+                },
+                Backend::Local => Arc::new(LocalStorage::new(root))
+            })
+        };
+
+        let (db, storage) = join!(t_db, t_storage).await;
+        let db = match &cipher {
+            Some(c) if encrypt_paths => db?.encrypted(c.clone()),
+            _ => db?
+        };
 
         let bd = Self {
-            db: db?,
-            ssh: ssh?,
+            db: Arc::new(db),
+            storage: storage?,
+            cipher,
+            compression: cfg.compression,
+            jobs: cfg.jobs.max(1),
+            state_lock: Arc::new(Mutex::new(())),
+            in_flight: Arc::default(),
+            device: cfg.device.unwrap_or_else(|| gethostname::gethostname().to_string_lossy().into_owned()),
+            retention: cfg.retention,
+            trash: cfg.trash,
             paths: cfg.paths,
             exe_path: PathBuf::from(curdir)
         };
 
         Ok(bd)
     }
+
+    /// List the remote files under `path`.
+    pub async fn list(&self, path: &str) -> Result<Vec<File<Remote>>, DatabaseError> {
+        self.db.list(&self.canonicalize_prefix(path)).await
+    }
+
+}
+
+impl BDrive {
+    /// Run `f` on the storage from a thread of its own, storage calls block until the server
+    /// answers.
+    pub(crate) async fn on_storage<T: Send + 'static>(&self, f: impl FnOnce(&dyn Storage) -> T + Send + 'static) -> T {
+        let storage = self.storage.clone();
+        blocking(move || f(&*storage)).await
+    }
+
+    /// Like [`BDrive::on_storage`], for work that needs the rest of `self` too, e.g. decoding.
+    pub(crate) async fn off_runtime<T: Send + 'static>(&self, f: impl FnOnce(&BDrive) -> T + Send + 'static) -> T {
+        let bd = self.clone();
+        blocking(move || f(&bd)).await
+    }
+}
+
+/// Run the blocking `f` on a thread of its own, so the tasks sharing the runtime go on meanwhile.
+/// A panic in `f` carries on in the caller.
+pub(crate) async fn blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    match tokio::task::spawn_blocking(f).await {
+        Ok(t) => t,
+        Err(e) => std::panic::resume_unwind(e.into_panic())
+    }
+}
+
+#[derive(Debug)]
+pub enum InitError {
+    /// The working directory is outside of the local root.
+    Outbound(PathBuf),
+    Config(String),
+    IO(std::io::Error),
+    DatabaseError(DatabaseError)
+}
+
+impl From<std::io::Error> for InitError {
+    fn from(value: std::io::Error) -> Self {
+        Self::IO(value)
+    }
+}
+
+impl From<DatabaseError> for InitError {
+    fn from(value: DatabaseError) -> Self {
+        Self::DatabaseError(value)
+    }
+}
+
+impl From<mongodb::error::Error> for InitError {
+    fn from(value: mongodb::error::Error) -> Self {
+        Self::DatabaseError(value.into())
+    }
 }
@@ -1,5 +1,7 @@
+use std::collections::HashSet;
+use std::path::{Component, Path};
 use walkdir::WalkDir;
-use crate::bdrive::BDrive;
+use crate::bdrive::{BDrive, STATE_DIR};
 use crate::conf::PathError;
 use crate::fs::File;
 use crate::fs::state::*;
@@ -21,31 +23,100 @@ impl BDrive {
         }
     }
 
-    fn canonicalize(&self, path: &str) -> String {
+    /// Turn a path given relative to the working directory into one relative to the local root.
+    /// Paths starting with `/` are already considered relative to the local root.
+    pub(crate) fn canonicalize(&self, path: &str) -> String {
         match path.strip_prefix('/') {
-            Some(strip) => strip.to_string(),
-            None => {
-                [self.exe_path.to_str().unwrap(), path].join("/")
-            }
+            Some(strip) => normalize(Path::new(strip)),
+            None => normalize(&self.exe_path.join(path))
+        }
+    }
+
+    /// Like `canonicalize`, but the local root becomes the empty string, which is a prefix of
+    /// every database path.
+    pub(crate) fn canonicalize_prefix(&self, path: &str) -> String {
+        match self.canonicalize(path) {
+            p if p == "." => String::new(),
+            p => p
         }
     }
 
     pub fn load_file(&self, path: &str) -> Result<File<Local>, PathSpecial> {
-        self.validate_path(self.canonicalize(path), |f| File::from(f))
+        let path = self.canonicalize(path);
+        if self.ignore_rules().is_ignored(&path, false) {
+            return Err(PathSpecial::Ignored(path))
+        }
+        self.validate_path(path, |f| File::from(f))
     }
 
+    /// Walk `path` looking for files. Ignored files and directories are reported as
+    /// `PathSpecial::Ignored`, and ignored directories are not descended into.
     pub fn scan_dir(&self, path: &str) -> Result<Vec<Result<File<Local>, PathSpecial>>, PathError> {
         let pc = self.canonicalize(path);
+        if !Path::new(&pc).exists() {
+            // nothing to scan, the path may still exist remotely
+            return Ok(vec![])
+        }
         self.paths.is_canonical(&pc)?;
-        Ok(WalkDir::new(self.canonicalize(path))
-            .into_iter()
-            .filter_map(|e| e.ok())
-            .filter(|e| e.file_type().is_file())
-            .map(|f| {
-                self.validate_path(f.path().to_str().unwrap().to_string(), |f| File::from(f))
-            })
-            .collect::<Vec<Result<File<Local>, PathSpecial>>>())
+        let rules = self.ignore_rules();
+
+        let mut found = vec![];
+        let mut walk = WalkDir::new(pc).into_iter();
+        while let Some(entry) = walk.next() {
+            let Ok(entry) = entry else { continue };
+            let is_dir = entry.file_type().is_dir();
+            if entry.file_name() == STATE_DIR {
+                if is_dir {
+                    walk.skip_current_dir();
+                }
+                continue
+            }
+
+            let path = normalize(entry.path());
+            if path != "." && rules.is_ignored(&path, is_dir) {
+                if is_dir {
+                    walk.skip_current_dir();
+                }
+                found.push(Err(PathSpecial::Ignored(path)));
+            } else if entry.file_type().is_file() {
+                found.push(self.validate_path(path, |f| File::from(f)));
+            }
+        }
+        Ok(found)
+    }
+}
+
+/// Lexically clean a relative path: drop `.` components and resolve `..` where possible, so the
+/// same file always maps to the same database path. The local root itself is `.`.
+pub(crate) fn normalize(path: &Path) -> String {
+    let mut parts: Vec<&str> = vec![];
+    for c in path.components() {
+        match c {
+            Component::CurDir => {},
+            Component::ParentDir => match parts.last() {
+                Some(&p) if p != ".." => { parts.pop(); },
+                _ => parts.push("..")
+            },
+            c => parts.push(c.as_os_str().to_str().unwrap())
+        }
+    }
+    if parts.is_empty() {
+        ".".to_string()
+    } else {
+        parts.join("/")
+    }
+}
+
+/// Whether `path` or one of its ancestors is in `paths`.
+pub(crate) fn is_covered(paths: &HashSet<String>, path: &str) -> bool {
+    let mut p = Some(Path::new(path));
+    while let Some(c) = p.filter(|c| !c.as_os_str().is_empty()) {
+        if paths.contains(c.to_str().unwrap()) {
+            return true
+        }
+        p = c.parent();
     }
+    false
 }
 
 impl From<PathError> for PathSpecial {
@@ -0,0 +1,243 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::info;
+use serde::Serialize;
+use super::{BDrive, BlobError};
+use super::snapshots::snapshot_chunks;
+use crate::conf::RetentionConfig;
+use crate::db::{Chunk, DatabaseError, Snapshot, Version};
+use crate::storage::StorageError;
+
+/// What a prune removed, or would remove on a dry run.
+#[derive(Serialize, Debug, Default)]
+pub struct PruneReport {
+    /// Expired versions: path and number.
+    pub versions: Vec<(String, u64)>,
+    /// Names of the expired snapshots.
+    pub snapshots: Vec<String>,
+    /// Blobs nothing uses once the expired records are gone.
+    pub blobs: usize,
+    /// Stored size of those blobs.
+    pub bytes: u64
+}
+
+impl Display for PruneReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (path, number) in &self.versions {
+            writeln!(f, "{:<10} {} {}", "version", number, path)?;
+        }
+        for name in &self.snapshots {
+            writeln!(f, "{:<10} {}", "snapshot", name)?;
+        }
+        Ok(())
+    }
+}
+
+impl BDrive {
+    /// Remove the versions and snapshots the retention policy doesn't keep, and free the blobs
+    /// only they used. Versions are judged file by file and snapshots root by root. What would
+    /// be freed is worked out from the blob records before anything is removed; with `dry_run`
+    /// that's all it does.
+    pub async fn prune(&self, dry_run: bool) -> Result<PruneReport, PruneError> {
+        let policy = self.retention.ok_or(PruneError::NoPolicy)?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+        let mut report = PruneReport::default();
+
+        // versions come sorted by path and number
+        let versions = self.db.list_versions("").await?;
+        let mut expired_versions: Vec<&Version> = vec![];
+        for file in versions.chunk_by(|a, b| a.path == b.path) {
+            let keep = kept(&file.iter().map(|v| v.time).collect::<Vec<_>>(), &policy, now);
+            expired_versions.extend(file.iter().enumerate().filter(|(i, _)| !keep.contains(i)).map(|(_, v)| v));
+        }
+
+        let mut roots: BTreeMap<String, Vec<Snapshot>> = BTreeMap::new();
+        for s in self.db.snapshots().await? {
+            roots.entry(s.root.clone()).or_default().push(s);
+        }
+        let mut expired_snapshots: Vec<Snapshot> = vec![];
+        for (_, snapshots) in roots {
+            let keep = kept(&snapshots.iter().map(|s| s.time).collect::<Vec<_>>(), &policy, now);
+            expired_snapshots.extend(snapshots.into_iter().enumerate().filter(|(i, _)| !keep.contains(i)).map(|(_, s)| s));
+        }
+
+        // count the references that go away to tell which blobs end up unused
+        let mut refs: HashMap<String, (u64, i64)> = self.db.blobs().await?.into_iter()
+            .map(|b| (b.hash, (b.size, b.refs)))
+            .collect();
+        let mut expired_chunks: Vec<Chunk> = expired_versions.iter().flat_map(|v| v.chunks.clone()).collect();
+        expired_chunks.extend(expired_snapshots.iter().flat_map(snapshot_chunks));
+        let mut touched = HashSet::new();
+        for chunk in &expired_chunks {
+            if let Some(r) = refs.get_mut(chunk.blob()) {
+                r.1 -= 1;
+                touched.insert(chunk.blob());
+            }
+        }
+        for blob in touched {
+            let (size, count) = refs[blob];
+            if count <= 0 {
+                report.blobs += 1;
+                report.bytes += size;
+            }
+        }
+        report.versions = expired_versions.iter().map(|v| (v.path.clone(), v.number)).collect();
+        report.snapshots = expired_snapshots.iter().map(|s| s.name.clone()).collect();
+        info!(
+            "{} versions and {} snapshots expired, freeing {} bytes in {} blobs",
+            report.versions.len(), report.snapshots.len(), report.bytes, report.blobs
+        );
+        if dry_run {
+            return Ok(report)
+        }
+
+        for v in expired_versions {
+            if self.db.delete_version(&v.path, v.number).await? {
+                self.release_content(&v.chunks).await?;
+            }
+        }
+        for s in expired_snapshots {
+            if self.db.delete_snapshot(&s.name).await? {
+                self.release_content(&snapshot_chunks(&s)).await?;
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Indexes of the items taken at `times`, oldest first, that `policy` keeps at time `now`.
+fn kept(times: &[u64], policy: &RetentionConfig, now: u64) -> HashSet<usize> {
+    let newest_first = (0..times.len()).rev();
+    let mut keep: HashSet<usize> = newest_first.clone().take(policy.last.max(1)).collect();
+    for (count, period) in [(policy.daily, day as Period), (policy.weekly, week), (policy.monthly, month)] {
+        let current = period(now);
+        let mut covered = HashSet::new();
+        for i in newest_first.clone() {
+            let p = period(times[i]);
+            if current.saturating_sub(p) < count && covered.insert(p) {
+                keep.insert(i);
+            }
+        }
+    }
+    keep
+}
+
+/// Number of the day, week or month a time falls in.
+type Period = fn(u64) -> u64;
+
+/// Days since the Unix epoch, in UTC.
+fn day(time: u64) -> u64 {
+    time / 86400
+}
+
+/// Weeks since the Unix epoch, starting on Monday: the epoch was a Thursday.
+fn week(time: u64) -> u64 {
+    (day(time) + 3) / 7
+}
+
+/// Months since the year 0, from the civil date of `time` (Howard Hinnant's algorithm).
+fn month(time: u64) -> u64 {
+    let z = day(time) + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + u64::from(m <= 2);
+    y * 12 + m - 1
+}
+
+#[derive(Debug)]
+pub enum PruneError {
+    /// There's no `[retention]` section in the configuration.
+    NoPolicy,
+    StorageError(StorageError),
+    DatabaseError(DatabaseError)
+}
+
+impl From<DatabaseError> for PruneError {
+    fn from(value: DatabaseError) -> Self {
+        PruneError::DatabaseError(value)
+    }
+}
+
+impl From<BlobError> for PruneError {
+    fn from(value: BlobError) -> Self {
+        match value {
+            BlobError::StorageError(e) => PruneError::StorageError(e),
+            BlobError::DatabaseError(e) => PruneError::DatabaseError(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = 86400;
+    // midnight UTC of some dates
+    const FEB_24_2024: u64 = 1708732800;
+    const FEB_29_2024: u64 = 1709164800;
+    const MAR_1_2024: u64 = 1709251200;
+    const MAR_3_2024: u64 = 1709424000;
+    const MAR_4_2024: u64 = 1709510400;
+
+    fn policy(last: usize, daily: u64, weekly: u64, monthly: u64) -> RetentionConfig {
+        RetentionConfig { last, daily, weekly, monthly }
+    }
+
+    fn sorted(keep: HashSet<usize>) -> Vec<usize> {
+        let mut keep: Vec<usize> = keep.into_iter().collect();
+        keep.sort();
+        keep
+    }
+
+    #[test]
+    fn periods() {
+        assert_eq!(day(DAY - 1), 0);
+        assert_eq!(day(DAY), 1);
+        // weeks start on Monday
+        assert_eq!(week(0), week(3 * DAY));
+        assert_eq!(week(4 * DAY), 1);
+        assert_eq!(week(MAR_3_2024) + 1, week(MAR_4_2024));
+        assert_eq!(week(MAR_4_2024), week(MAR_4_2024 + 6 * DAY + DAY - 1));
+        assert_eq!(month(0), 1970 * 12);
+        assert_eq!(month(FEB_29_2024 + DAY - 1), 2024 * 12 + 1);
+        assert_eq!(month(MAR_1_2024), 2024 * 12 + 2);
+        assert_eq!(month(978307199), 2000 * 12 + 11);
+        assert_eq!(month(978307200), 2001 * 12);
+    }
+
+    #[test]
+    fn newest_always_kept() {
+        assert_eq!(sorted(kept(&[1, 2, 3, 4], &policy(0, 0, 0, 0), 10)), [3]);
+        assert_eq!(sorted(kept(&[1, 2, 3, 4], &policy(2, 0, 0, 0), 10)), [2, 3]);
+        assert_eq!(sorted(kept(&[1, 2], &policy(5, 0, 0, 0), 10)), [0, 1]);
+        assert!(kept(&[], &policy(5, 3, 2, 1), 10).is_empty());
+    }
+
+    #[test]
+    fn daily() {
+        let noon = DAY / 2;
+        let times = [5 * DAY, 7 * DAY, 8 * DAY, 8 * DAY + noon, 9 * DAY, 10 * DAY];
+        // the newest of days 8, 9 and 10
+        assert_eq!(sorted(kept(&times, &policy(1, 3, 0, 0), 10 * DAY + noon)), [3, 4, 5]);
+        // days without anything still count
+        assert_eq!(sorted(kept(&times, &policy(1, 3, 0, 0), 12 * DAY)), [5]);
+    }
+
+    #[test]
+    fn weekly_and_monthly() {
+        let times = [FEB_24_2024, MAR_3_2024, MAR_4_2024];
+        let now = MAR_4_2024 + DAY;
+        assert_eq!(sorted(kept(&times, &policy(1, 0, 1, 0), now)), [2]);
+        assert_eq!(sorted(kept(&times, &policy(1, 0, 2, 0), now)), [1, 2]);
+        assert_eq!(sorted(kept(&times, &policy(1, 0, 3, 0), now)), [0, 1, 2]);
+
+        let times = [FEB_29_2024 - 40 * DAY, FEB_24_2024, FEB_29_2024, MAR_4_2024];
+        assert_eq!(sorted(kept(&times, &policy(1, 0, 0, 2), now)), [2, 3]);
+        assert_eq!(sorted(kept(&times, &policy(1, 0, 0, 3), now)), [0, 2, 3]);
+    }
+}
@@ -0,0 +1,132 @@
+use log::{info, warn};
+use super::{BDrive, SyncBase};
+use crate::conf::PathError;
+use crate::fs::{File, state::*, Upload};
+use crate::db::DatabaseError;
+
+impl BDrive {
+    /// This function moves a file to a new path, in the database and, if present, in the local
+    /// tree. The stored content is addressed by hash, so the storage is left untouched.
+    pub async fn rename(&mut self, from: &str, to: &str) -> Result<File<Remote>, RenameError> {
+        let from = self.canonicalize(from);
+        let to = self.canonicalize(to);
+
+        let remote = match self.db.get_file_path(&from).await {
+            Ok(Some(r)) => r,
+            Ok(None) => return Err(RenameError::NotFound(from)),
+            Err(e) => return Err(RenameError::DatabaseError(from, e))
+        };
+        match self.db.exists(&to).await {
+            Ok(false) => {},
+            Ok(true) => return Err(RenameError::Exists(to)),
+            Err(e) => return Err(RenameError::DatabaseError(to, e))
+        }
+        let (src, dst) = match (self.paths.to_local(&from), self.paths.to_local(&to)) {
+            (Ok(s), Ok(d)) => (s, d),
+            (Err(e), _) | (_, Err(e)) => return Err(RenameError::PathError(e))
+        };
+        // an untracked local file at `to` would be overwritten by the move
+        if src.is_file() && dst.exists() {
+            return Err(RenameError::Exists(to))
+        }
+
+        if let Err(e) = self.db.rename(&from, &to).await {
+            return Err(RenameError::DatabaseError(from, e))
+        }
+        // versions left behind would be picked up by the next file created at `from`
+        if let Err(e) = self.db.rename_versions(&from, &to).await {
+            if let Err(e) = self.db.rename(&to, &from).await {
+                warn!("cannot move {} back to {}: {:?}", to, from, e);
+            }
+            return Err(RenameError::DatabaseError(from, e))
+        }
+
+        if src.is_file() {
+            info!("moving local {} to {}", from, to);
+            let moved = match dst.parent() {
+                Some(parent) => std::fs::create_dir_all(parent).and_then(|_| std::fs::rename(&src, &dst)),
+                None => std::fs::rename(&src, &dst)
+            };
+            moved.map_err(|e| RenameError::IOError(to.clone(), e))?;
+        }
+
+        let mut base = self.load_state::<SyncBase>().map_err(|(p, e)| RenameError::IOError(p, e))?;
+        if let Some(id) = base.get(&from).cloned() {
+            base.remove(&from);
+            base.set(to.clone(), id);
+            self.save_state(&base).map_err(|(p, e)| RenameError::IOError(p, e))?;
+        }
+
+        Ok(File::new(to, Remote { remote: remote.remote_identity() }))
+    }
+
+    /// This function copies a file to a new path. The copy shares the stored content of the
+    /// original, only a new record, starting its own history, and references to the blobs are
+    /// added. If the original is present in the local tree it's copied there too.
+    pub async fn copy(&mut self, from: &str, to: &str) -> Result<File<Remote>, RenameError> {
+        let from = self.canonicalize(from);
+        let to = self.canonicalize(to);
+
+        let remote = match self.db.get_file_path(&from).await {
+            Ok(Some(r)) => r,
+            Ok(None) => return Err(RenameError::NotFound(from)),
+            Err(e) => return Err(RenameError::DatabaseError(from, e))
+        };
+        match self.db.exists(&to).await {
+            Ok(false) => {},
+            Ok(true) => return Err(RenameError::Exists(to)),
+            Err(e) => return Err(RenameError::DatabaseError(to, e))
+        }
+        let (src, dst) = match (self.paths.to_local(&from), self.paths.to_local(&to)) {
+            (Ok(s), Ok(d)) => (s, d),
+            (Err(e), _) | (_, Err(e)) => return Err(RenameError::PathError(e))
+        };
+
+        let chunks = match self.chunks(&from).await {
+            Ok(c) => c,
+            Err(e) => return Err(RenameError::DatabaseError(from, e))
+        };
+        let id = remote.remote_identity();
+        let copy = File::new(to.clone(), Remote { remote: id.clone() });
+        if let Err(e) = self.ref_content(&chunks).await {
+            return Err(RenameError::DatabaseError(to, e))
+        }
+        if let Err(e) = self.db.create_remote(&copy, chunks.clone()).await {
+            warn!("cannot add {} to database, releasing its content", to);
+            if let Err(e) = self.release_content(&chunks).await {
+                warn!("cannot release content of {}: {:?}", to, e);
+            }
+            return Err(RenameError::DatabaseError(to, e))
+        }
+        self.record_version(&to, &id, &chunks).await;
+
+        if src.is_file() && !dst.exists() {
+            info!("copying local {} to {}", from, to);
+            let copied = match dst.parent() {
+                Some(parent) => std::fs::create_dir_all(parent).and_then(|_| std::fs::copy(&src, &dst)),
+                None => std::fs::copy(&src, &dst)
+            };
+            copied.map_err(|e| RenameError::IOError(to.clone(), e))?;
+            // the local original may have changed since the last sync, record the copy as synced
+            // only if it holds the stored content.
+            if let Ok(local) = File::from(dst).hash() {
+                if local.local_identity() == id {
+                    let mut base = self.load_state::<SyncBase>().map_err(|(p, e)| RenameError::IOError(p, e))?;
+                    base.set(to.clone(), id);
+                    self.save_state(&base).map_err(|(p, e)| RenameError::IOError(p, e))?;
+                }
+            }
+        }
+
+        Ok(copy)
+    }
+}
+
+#[derive(Debug)]
+pub enum RenameError {
+    NotFound(String),
+    Exists(String),
+    PathError(PathError),
+    IOError(String, std::io::Error),
+    DatabaseError(String, DatabaseError)
+}
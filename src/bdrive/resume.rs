@@ -0,0 +1,133 @@
+use std::collections::{HashMap, HashSet};
+use log::warn;
+use serde::{Serialize, Deserialize};
+use super::BDrive;
+use super::state::StateFile;
+
+/// Progress of the uploads that didn't complete, so a retry knows how far the previous attempt
+/// got. It's only a hint: the storage is still checked for every chunk.
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct UploadJournal {
+    uploads: HashMap<String, Progress>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Progress {
+    /// Hash of the whole file, if it changed since the progress is worthless.
+    hash: String,
+    /// Blobs known to be stored.
+    stored: HashSet<String>,
+    /// Bytes of the file covered by the stored chunks.
+    bytes: u64
+}
+
+impl UploadJournal {
+    /// Start or resume the upload of `path` with content `hash`, returns the bytes already
+    /// covered by a previous attempt.
+    pub fn start(&mut self, path: &str, hash: &str) -> u64 {
+        match self.uploads.get(path) {
+            Some(p) if p.hash == hash => p.bytes,
+            _ => {
+                self.uploads.insert(path.to_string(), Progress { hash: hash.to_string(), stored: HashSet::new(), bytes: 0 });
+                0
+            }
+        }
+    }
+
+    pub fn stored(&mut self, path: &str, blob: &str, size: u64) {
+        if let Some(p) = self.uploads.get_mut(path) {
+            if p.stored.insert(blob.to_string()) {
+                p.bytes += size;
+            }
+        }
+    }
+
+    pub fn finish(&mut self, path: &str) {
+        self.uploads.remove(path);
+    }
+
+    /// Forget the uploads that stored one of `blobs`, which are gone. Returns how many.
+    pub fn forget(&mut self, blobs: &HashSet<String>) -> usize {
+        let before = self.uploads.len();
+        self.uploads.retain(|_, p| p.stored.is_disjoint(blobs));
+        before - self.uploads.len()
+    }
+}
+
+impl StateFile for UploadJournal {
+    const NAME: &'static str = "uploads.json";
+}
+
+/// Bytes of progress an upload gathers before writing them to the journal.
+const JOURNAL_BATCH: u64 = 64 << 20;
+
+/// Blobs stored by an upload and not written to the journal yet. They're written every
+/// [`JOURNAL_BATCH`] bytes and when the batch is dropped, also on errors, so the journal isn't
+/// rewritten for every chunk of a large file.
+pub(crate) struct JournalBatch<'a> {
+    bd: &'a BDrive,
+    path: String,
+    blobs: Vec<(String, u64)>,
+    bytes: u64
+}
+
+impl JournalBatch<'_> {
+    /// Record that the blob `blob`, covering `size` bytes of the file, is stored.
+    pub(crate) fn stored(&mut self, blob: &str, size: u64) {
+        self.blobs.push((blob.to_string(), size));
+        self.bytes += size;
+        if self.bytes >= JOURNAL_BATCH {
+            self.flush();
+        }
+    }
+
+    /// The upload is complete, its progress can go.
+    pub(crate) fn finish(mut self) {
+        self.blobs.clear();
+        self.bd.update_journal(|j| j.finish(&self.path));
+    }
+
+    fn flush(&mut self) {
+        if self.blobs.is_empty() {
+            return
+        }
+        let blobs = std::mem::take(&mut self.blobs);
+        self.bytes = 0;
+        self.bd.update_journal(|j| {
+            for (blob, size) in blobs {
+                j.stored(&self.path, &blob, size);
+            }
+        });
+    }
+}
+
+impl Drop for JournalBatch<'_> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+impl BDrive {
+    /// Start or resume recording the upload of `path` with content `hash` in the journal, returns
+    /// the batch to record its progress in and the bytes covered by a previous attempt.
+    pub(crate) fn journal_upload(&self, path: &str, hash: &str) -> (JournalBatch<'_>, u64) {
+        let resumed = self.update_journal(|j| j.start(path, hash));
+        (JournalBatch { bd: self, path: path.to_string(), blobs: vec![], bytes: 0 }, resumed)
+    }
+
+    /// Apply `f` to the upload journal on disk and write it back. Concurrent transfers share the
+    /// journal, so it's read again each time under the state lock. The journal only saves work,
+    /// failures are logged.
+    pub(crate) fn update_journal<T>(&self, f: impl FnOnce(&mut UploadJournal) -> T) -> T {
+        let _lock = self.state_lock.lock().unwrap();
+        let mut journal = self.load_state::<UploadJournal>().unwrap_or_else(|(p, e)| {
+            warn!("cannot read upload journal {}: {:?}", p, e);
+            UploadJournal::default()
+        });
+        let result = f(&mut journal);
+        if let Err((p, e)) = self.save_state(&journal) {
+            warn!("cannot write upload journal {}: {:?}", p, e);
+        }
+        result
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use futures::{stream, StreamExt};
+use log::{info, warn};
+use super::{BDrive, BlobError, DeleteError, DownloadError};
+use crate::db::{Chunk, DatabaseError, Snapshot};
+use crate::fs::state::*;
+use crate::storage::StorageError;
+
+/// What restoring a snapshot changed.
+#[derive(Debug, Default)]
+pub struct SnapshotRestore {
+    /// Files given back the content they had in the snapshot.
+    pub restored: Vec<String>,
+    /// Files created after the snapshot, removed from the remote.
+    pub deleted: Vec<String>,
+    /// Snapshot of the state that was replaced, taken before restoring in place.
+    pub backup: Option<String>,
+    pub errors: Vec<SnapshotError>
+}
+
+impl BDrive {
+    /// Record the remote files inside directory `path` as they are now, under `name` or the
+    /// current time. Only the database changes: the snapshot references the stored content of
+    /// its files, which stays stored until the snapshot is deleted.
+    pub async fn snapshot(&self, path: &str, name: Option<String>) -> Result<Snapshot, SnapshotError> {
+        let root = self.canonicalize_prefix(path);
+        self.migrate_dir(&root).await?;
+        let now = SystemTime::now();
+        let snapshot = Snapshot {
+            name: name.unwrap_or_else(|| humantime::format_rfc3339_seconds(now).to_string()),
+            time: now.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+            device: self.device.clone(),
+            files: self.db.records(&root).await?,
+            root
+        };
+        if self.db.get_snapshot(&snapshot.name).await?.is_some() {
+            return Err(SnapshotError::Exists(snapshot.name))
+        }
+
+        let chunks = snapshot_chunks(&snapshot);
+        self.ref_content(&chunks).await?;
+        if let Err(e) = self.db.add_snapshot(snapshot.clone()).await {
+            if let Err(e) = self.release_content(&chunks).await {
+                warn!("cannot release chunks: {:?}", e);
+            }
+            return Err(match e {
+                DatabaseError::Duplicate(name) => SnapshotError::Exists(name),
+                e => SnapshotError::DatabaseError(e)
+            })
+        }
+        info!("snapshot {} holds {} files", snapshot.name, snapshot.files.len());
+        Ok(snapshot)
+    }
+
+    /// Every snapshot, oldest first.
+    pub async fn snapshots(&self) -> Result<Vec<Snapshot>, SnapshotError> {
+        Ok(self.db.snapshots().await?)
+    }
+
+    /// Remove the snapshot `name` and release its content.
+    pub async fn delete_snapshot(&self, name: &str) -> Result<Snapshot, SnapshotError> {
+        let snapshot = self.get_snapshot(name).await?;
+        if !self.db.delete_snapshot(name).await? {
+            return Err(SnapshotError::NotFound(name.to_string()))
+        }
+        self.release_content(&snapshot_chunks(&snapshot)).await?;
+        Ok(snapshot)
+    }
+
+    /// Bring the remote files inside the root of snapshot `name` back to how they were: files
+    /// that changed get their old content as a new version, files created since are deleted.
+    /// The replaced state is kept in a snapshot first, so this can be undone. Like restoring a
+    /// version only the database changes, the local tree is updated by the next sync.
+    pub async fn restore_snapshot(&mut self, name: &str) -> Result<SnapshotRestore, SnapshotError> {
+        let snapshot = self.get_snapshot(name).await?;
+        let mut report = SnapshotRestore::default();
+
+        let now = humantime::format_rfc3339_seconds(SystemTime::now());
+        let backup = self.snapshot(&format!("/{}", snapshot.root), Some(format!("before-{}-{}", name, now))).await?;
+        report.backup = Some(backup.name);
+
+        let kept: HashSet<&str> = snapshot.files.iter().map(|f| f.path.as_str()).collect();
+        for current in backup.files.iter().filter(|f| !kept.contains(f.path.as_str())) {
+            match self.delete(&format!("/{}", current.path)).await {
+                Ok(f) => report.deleted.push(f.path),
+                Err(e) => report.errors.push(SnapshotError::DeleteError(Box::new(e)))
+            }
+        }
+
+        for f in snapshot.files {
+            let unchanged = backup.files.iter().any(|c| c.path == f.path && c.hash == f.hash && c.size == f.size);
+            if unchanged {
+                continue
+            }
+            let chunks = f.chunks();
+            let id = Identity::new(f.hash, f.size);
+            match self.replace_content(&f.path, id, &chunks).await {
+                Ok(r) => report.restored.push(r.path),
+                Err(e) => report.errors.push(SnapshotError::DatabaseError(e))
+            }
+        }
+        info!("restored {} files and deleted {} from snapshot {}", report.restored.len(), report.deleted.len(), name);
+        if !report.deleted.is_empty() {
+            self.purge_expired().await;
+        }
+        Ok(report)
+    }
+
+    /// Download the files of snapshot `name` into the local directory `dir`, at their paths
+    /// relative to the root of the tree. Neither the remote nor the tree change.
+    pub async fn restore_snapshot_into(&self, name: &str, dir: &Path) -> Result<SnapshotRestore, SnapshotError> {
+        let snapshot = self.get_snapshot(name).await?;
+        // relative to where bdrive was started, not to the root it moved to
+        let dir = Path::new(&self.paths.local).join(&self.exe_path).join(dir);
+        let mut report = SnapshotRestore::default();
+
+        let mut fetched = stream::iter(snapshot.files)
+            .map(|f| {
+                let dest = dir.join(&f.path);
+                let chunks = f.chunks();
+                let remote = f.to_local();
+                self.off_runtime(move |bd| bd.fetch(remote, &chunks, &dest))
+            })
+            .buffer_unordered(self.jobs);
+        while let Some(f) = fetched.next().await {
+            match f {
+                Ok(f) => report.restored.push(f.path),
+                Err(e) => report.errors.push(SnapshotError::DownloadError(Box::new(e)))
+            }
+        }
+        info!("restored {} files of snapshot {} into {}", report.restored.len(), name, dir.display());
+        Ok(report)
+    }
+
+    async fn get_snapshot(&self, name: &str) -> Result<Snapshot, SnapshotError> {
+        match self.db.get_snapshot(name).await? {
+            Some(s) => Ok(s),
+            None => Err(SnapshotError::NotFound(name.to_string()))
+        }
+    }
+}
+
+/// Chunks referenced by a snapshot, once for each file using them.
+pub(super) fn snapshot_chunks(snapshot: &Snapshot) -> Vec<Chunk> {
+    snapshot.files.iter().flat_map(|f| f.chunks()).collect()
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    NotFound(String),
+    Exists(String),
+    DownloadError(Box<DownloadError>),
+    DeleteError(Box<DeleteError>),
+    StorageError(StorageError),
+    DatabaseError(DatabaseError)
+}
+
+impl From<DatabaseError> for SnapshotError {
+    fn from(value: DatabaseError) -> Self {
+        SnapshotError::DatabaseError(value)
+    }
+}
+
+impl From<BlobError> for SnapshotError {
+    fn from(value: BlobError) -> Self {
+        match value {
+            BlobError::StorageError(e) => SnapshotError::StorageError(e),
+            BlobError::DatabaseError(e) => SnapshotError::DatabaseError(e)
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use std::path::PathBuf;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use super::{BDrive, STATE_DIR};
+
+/// A JSON file kept in the state directory of the tree.
+pub(crate) trait StateFile: Serialize + DeserializeOwned + Default {
+    /// Name of the file inside the state directory.
+    const NAME: &'static str;
+}
+
+impl BDrive {
+    /// Path of a file inside the state directory.
+    pub(crate) fn state_path(&self, name: &str) -> PathBuf {
+        [self.paths.local.as_str(), STATE_DIR, name].iter().collect()
+    }
+
+    /// Load the state file `T` of this tree, a missing file gives the default. Errors carry the
+    /// path of the file.
+    pub(crate) fn load_state<T: StateFile>(&self) -> Result<T, (String, std::io::Error)> {
+        let path = self.state_path(T::NAME);
+        let loaded = match std::fs::read_to_string(&path) {
+            Ok(s) => serde_json::from_str(&s).map_err(std::io::Error::from),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+            Err(e) => Err(e)
+        };
+        loaded.map_err(|e| (path.to_string_lossy().to_string(), e))
+    }
+
+    /// Write the state file `T` of this tree, creating the state directory if needed. It's
+    /// written aside and renamed in place, so an interruption leaves the previous state.
+    pub(crate) fn save_state<T: StateFile>(&self, state: &T) -> Result<(), (String, std::io::Error)> {
+        let path = self.state_path(T::NAME);
+        let tmp = path.with_extension("json.tmp");
+        let saved = path.parent().map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| Ok(serde_json::to_string(state)?))
+            .and_then(|json| std::fs::write(&tmp, json))
+            .and_then(|_| std::fs::rename(&tmp, &path));
+        saved.map_err(|e| (path.to_string_lossy().to_string(), e))
+    }
+}
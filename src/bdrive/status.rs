@@ -0,0 +1,155 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime};
+use std::fmt::{Display, Formatter};
+use serde::Serialize;
+use super::{BDrive, PathSpecial, SyncBase};
+use super::paths::is_covered;
+use crate::conf::PathError;
+use crate::fs::{FileSuccess, SyncState, Upload, LocalFile, Split};
+use crate::fs::state::Identity;
+use crate::db::{DatabaseError, Tombstone};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileStatus {
+    /// Only in the local tree.
+    New,
+    /// In both, but with different content.
+    Modified,
+    Synced,
+    /// Only on the remote.
+    RemoteOnly,
+    /// Only in the local tree, and deleted from the remote since it was synced.
+    Deleted,
+    /// Excluded from syncing.
+    Ignored
+}
+
+impl FileStatus {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::New => "new",
+            Self::Modified => "modified",
+            Self::Synced => "synced",
+            Self::RemoteOnly => "remote",
+            Self::Deleted => "deleted",
+            Self::Ignored => "ignored"
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct StatusEntry {
+    pub path: String,
+    pub status: FileStatus,
+    pub local: Option<Identity>,
+    pub remote: Option<Identity>,
+    /// Who deleted a `Deleted` file and when, if known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tombstone: Option<Tombstone>
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct StatusReport {
+    pub entries: Vec<StatusEntry>
+}
+
+impl StatusReport {
+    pub fn count(&self, status: FileStatus) -> usize {
+        self.entries.iter().filter(|e| e.status == status).count()
+    }
+
+    fn push(&mut self, path: String, status: FileStatus, local: Option<Identity>, remote: Option<Identity>) {
+        self.entries.push(StatusEntry { path, status, local, remote, tombstone: None })
+    }
+}
+
+/// Lists every entry that needs attention, synced files are left out.
+impl Display for StatusReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for e in self.entries.iter().filter(|e| e.status != FileStatus::Synced) {
+            match &e.tombstone {
+                Some(t) => {
+                    let time = SystemTime::UNIX_EPOCH + Duration::from_secs(t.deleted);
+                    writeln!(f, "{:<10} {} (by {}, {})", e.status.name(), e.path, t.device, humantime::format_rfc3339_seconds(time))?;
+                }
+                None => writeln!(f, "{:<10} {}", e.status.name(), e.path)?
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BDrive {
+    /// Compare every file under `path` with its database record, without transferring anything.
+    /// A file only in the local tree is new, unless it's still what was synced last or what a
+    /// tombstone says was deleted: then the remote copy was deleted.
+    pub async fn status(&self, path: &str) -> Result<StatusReport, StatusError> {
+        let dir = self.canonicalize_prefix(path);
+        let base = self.load_state::<SyncBase>().map_err(|(p, e)| StatusError::IOError(p, e))?;
+        let mut tombstones: HashMap<String, Tombstone> = self.db.tombstones(&dir).await
+            .map_err(StatusError::DatabaseError)?
+            .into_iter()
+            .map(|t| (t.path.clone(), t))
+            .collect();
+        let mut report = StatusReport::default();
+        let mut seen = HashSet::new();
+
+        for f in self.scan_dir(&format!("/{}", dir))? {
+            let f = match f {
+                Ok(f) => f,
+                Err(PathSpecial::Ignored(p)) => {
+                    seen.insert(p.clone());
+                    report.push(p, FileStatus::Ignored, None, None);
+                    continue
+                }
+                Err(_) => continue
+            };
+            let f = f.hash().map_err(|(e, f)| StatusError::IOError(f.path(), e))?;
+            seen.insert(f.path());
+
+            match self.db.get_file_file(f).await {
+                Ok(FileSuccess::Yes(SyncState::Sync(s))) => {
+                    let id = s.identity();
+                    report.push(s.path(), FileStatus::Synced, Some(id.clone()), Some(id));
+                }
+                Ok(FileSuccess::Yes(SyncState::Diff(d))) => {
+                    let (local, remote) = d.split();
+                    report.push(local.path(), FileStatus::Modified, Some(local.local_identity()), Some(remote.remote_identity()));
+                }
+                Ok(FileSuccess::No((), n)) => {
+                    let (path, id) = (n.path(), n.local_identity());
+                    let tombstone = tombstones.remove(&path).filter(|t| t.identity() == id);
+                    if base.get(&path) == Some(&id) || tombstone.is_some() {
+                        report.entries.push(StatusEntry { path, status: FileStatus::Deleted, local: Some(id), remote: None, tombstone });
+                    } else {
+                        report.push(path, FileStatus::New, Some(id), None);
+                    }
+                }
+                Err((e, _)) => return Err(StatusError::DatabaseError(e))
+            }
+        }
+
+        for r in self.db.list(&dir).await.map_err(StatusError::DatabaseError)? {
+            if !is_covered(&seen, &r.path) {
+                report.push(r.path(), FileStatus::RemoteOnly, None, Some(r.remote_identity()));
+            }
+        }
+
+        report.entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(report)
+    }
+}
+
+#[derive(Debug)]
+pub enum StatusError {
+    PathError(PathError),
+    IOError(String, std::io::Error),
+    DatabaseError(DatabaseError)
+}
+
+impl From<PathError> for StatusError {
+    fn from(value: PathError) -> Self {
+        Self::PathError(value)
+    }
+}
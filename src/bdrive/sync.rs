@@ -0,0 +1,464 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use super::paths::is_covered;
+use futures::{stream, StreamExt};
+use super::{blocking, BDrive, SyncBase, UploadOptions, UploadError, DownloadOptions, DownloadError, DeleteError, PathSpecial};
+use super::transfer::progress_bar;
+use crate::conf::PathError;
+use crate::fs::{File, state::*, SyncState, Upload, LocalFile};
+use log::{info, warn};
+use crate::db::{DatabaseError, Tombstone};
+
+/// The local and the remote version of a path, if present.
+type Sides = (Option<File<LocalHashed>>, Option<File<Remote>>);
+
+/// Where a path stands when comparing the local tree, the database and the last synced state.
+#[derive(Debug)]
+pub enum SyncClass {
+    LocalOnly(File<LocalHashed>),
+    RemoteOnly(File<Remote>),
+    Equal(File<Sync>),
+    /// Only the local file changed since the last sync.
+    LocalNewer(File<Diff>),
+    /// Only the remote file changed since the last sync.
+    RemoteNewer(File<Diff>),
+    /// Both changed, or they were never synced from this machine.
+    Conflict(File<Diff>)
+}
+
+impl SyncClass {
+    fn classify(local: Option<File<LocalHashed>>, remote: Option<File<Remote>>, base: Option<&Identity>) -> Option<Self> {
+        match (local, remote) {
+            (Some(l), Some(r)) => Some(match l.attach_remote(r) {
+                SyncState::Sync(f) => Self::Equal(f),
+                SyncState::Diff(d) => match base {
+                    Some(b) if *b == d.local_identity() => Self::RemoteNewer(d),
+                    Some(b) if *b == d.remote_identity() => Self::LocalNewer(d),
+                    _ => Self::Conflict(d)
+                }
+            }),
+            (Some(l), None) => Some(Self::LocalOnly(l)),
+            (None, Some(r)) => Some(Self::RemoteOnly(r)),
+            (None, None) => None
+        }
+    }
+
+    /// Decide what to do with this path. A file missing on one side that is still identical to
+    /// the last synced version has been deleted on the other side, so the deletion is propagated;
+    /// if it was modified in the meantime the modification wins. A local file never synced from
+    /// here is deleted too if its `tombstone` says another machine deleted that very content.
+    fn plan(self, base: Option<&Identity>, tombstone: Option<&Tombstone>) -> SyncAction {
+        match self {
+            Self::LocalOnly(l) => match (base, tombstone) {
+                (Some(b), _) if *b == l.local_identity() => SyncAction::DeleteLocal(l),
+                (None, Some(t)) if t.identity() == l.local_identity() => SyncAction::DeleteLocal(l),
+                _ => SyncAction::Upload(l)
+            },
+            Self::RemoteOnly(r) => match base {
+                Some(b) if *b == r.remote_identity() => SyncAction::DeleteRemote(r),
+                _ => SyncAction::Download(r)
+            },
+            Self::Equal(f) => SyncAction::Keep(f),
+            Self::LocalNewer(d) => SyncAction::Push(d),
+            Self::RemoteNewer(d) => SyncAction::Pull(d),
+            Self::Conflict(d) => SyncAction::Conflict(d)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SyncAction {
+    /// Upload a file that isn't on the remote.
+    Upload(File<LocalHashed>),
+    /// Download a file that isn't in the local tree.
+    Download(File<Remote>),
+    /// Overwrite the remote file with the local one.
+    Push(File<Diff>),
+    /// Overwrite the local file with the remote one.
+    Pull(File<Diff>),
+    DeleteLocal(File<LocalHashed>),
+    DeleteRemote(File<Remote>),
+    /// Left untouched, must be solved by hand.
+    Conflict(File<Diff>),
+    Keep(File<Sync>)
+}
+
+impl SyncAction {
+    pub fn path(&self) -> String {
+        match self {
+            Self::Upload(f) | Self::DeleteLocal(f) => f.path(),
+            Self::Download(f) | Self::DeleteRemote(f) => f.path(),
+            Self::Push(f) | Self::Pull(f) | Self::Conflict(f) => f.path(),
+            Self::Keep(f) => f.path()
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Upload(_) => "upload",
+            Self::Download(_) => "download",
+            Self::Push(_) => "push",
+            Self::Pull(_) => "pull",
+            Self::DeleteLocal(_) => "rm local",
+            Self::DeleteRemote(_) => "rm remote",
+            Self::Conflict(_) => "conflict",
+            Self::Keep(_) => "keep"
+        }
+    }
+}
+
+impl Display for SyncAction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:<10} {}", self.name(), self.path())
+    }
+}
+
+/// What `BDrive::apply` is going to do, nothing is touched while building it.
+#[derive(Debug, Default)]
+pub struct SyncPlan {
+    pub actions: Vec<SyncAction>
+}
+
+impl SyncPlan {
+    /// Whether applying this plan would change anything.
+    pub fn is_noop(&self) -> bool {
+        self.actions.iter().all(|a| matches!(a, SyncAction::Keep(_)))
+    }
+
+    /// Keep only what sends local content to the remote. Remote deletions are not propagated, so
+    /// those files are uploaded again. Files changed only remotely are left alone and files
+    /// changed on both sides stay conflicts, unless `overwrite` replaces both with the local file.
+    pub fn push_only(self, overwrite: bool) -> Self {
+        Self {
+            actions: self.actions.into_iter().filter_map(|a| match a {
+                SyncAction::Upload(l) | SyncAction::DeleteLocal(l) => Some(SyncAction::Upload(l)),
+                SyncAction::Pull(d) | SyncAction::Conflict(d) if overwrite => Some(SyncAction::Push(d)),
+                SyncAction::Pull(_) | SyncAction::Download(_) | SyncAction::DeleteRemote(_) => None,
+                a => Some(a)
+            }).collect()
+        }
+    }
+
+    /// Keep only what brings remote content into the local tree. Local deletions are not
+    /// propagated, so those files are downloaded again. Files changed only locally are left alone
+    /// and files changed on both sides stay conflicts, unless `overwrite` replaces both with the
+    /// remote file.
+    pub fn pull_only(self, overwrite: bool) -> Self {
+        Self {
+            actions: self.actions.into_iter().filter_map(|a| match a {
+                SyncAction::Download(r) | SyncAction::DeleteRemote(r) => Some(SyncAction::Download(r)),
+                SyncAction::Push(d) | SyncAction::Conflict(d) if overwrite => Some(SyncAction::Pull(d)),
+                SyncAction::Push(_) | SyncAction::Upload(_) | SyncAction::DeleteLocal(_) => None,
+                a => Some(a)
+            }).collect()
+        }
+    }
+}
+
+impl Display for SyncPlan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for a in self.actions.iter().filter(|a| !matches!(a, SyncAction::Keep(_))) {
+            writeln!(f, "{}", a)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    pub synced: Vec<File<Sync>>,
+    pub deleted: Vec<String>,
+    pub conflicts: Vec<File<Diff>>,
+    pub errors: Vec<SyncError>
+}
+
+impl BDrive {
+    /// Compare the local directory `dir` with its remote counterpart and decide what to do with
+    /// every path in it.
+    pub async fn plan_sync(&self, dir: &str) -> Result<SyncPlan, SyncError> {
+        let dir = self.canonicalize_prefix(dir);
+        let base = self.load_state::<SyncBase>().map_err(|(p, e)| SyncError::IOError(p, e))?;
+
+        let mut entries: BTreeMap<String, Sides> = BTreeMap::new();
+        // paths that exist locally but must not be synced, their remote files are left alone
+        let mut special = HashSet::new();
+
+        let mut unhashed = vec![];
+        for f in self.scan_dir(&format!("/{}", dir))? {
+            match f {
+                Ok(f) => unhashed.push(f),
+                Err(PathSpecial::Outbound(p) | PathSpecial::Ignored(p) | PathSpecial::Malformed(p)) => {
+                    special.insert(p);
+                }
+                Err(PathSpecial::Unknown) => {}
+            }
+        }
+
+        // hashing reads every file, it's done `jobs` at a time
+        let mut hashed = stream::iter(unhashed)
+            .map(|f| blocking(move || f.hash()))
+            .buffer_unordered(self.jobs);
+        while let Some(f) = hashed.next().await {
+            let f = f.map_err(|(e, f)| SyncError::IOError(f.path(), e))?;
+            let path = f.path();
+            entries.entry(path).or_default().0 = Some(f);
+        }
+
+        for r in self.db.list(&dir).await.map_err(SyncError::DatabaseError)? {
+            if !is_covered(&special, &r.path) {
+                let path = r.path();
+                entries.entry(path).or_default().1 = Some(r);
+            }
+        }
+        let tombstones: HashMap<String, Tombstone> = self.db.tombstones(&dir).await
+            .map_err(SyncError::DatabaseError)?
+            .into_iter()
+            .map(|t| (t.path.clone(), t))
+            .collect();
+
+        let mut actions = vec![];
+        for (path, (l, r)) in entries {
+            let b = base.get(&path);
+            actions.push(match SyncClass::classify(l, r, b).map(|c| c.plan(b, tombstones.get(&path))) {
+                Some(SyncAction::Conflict(d)) => self.confirm_conflict(d).await,
+                Some(a) => a,
+                None => continue
+            });
+        }
+        Ok(SyncPlan { actions })
+    }
+
+    /// A conflict only stands if the stored content is what the database says. When it's
+    /// missing, or already is the local content, the local file is pushed over the record.
+    async fn confirm_conflict(&self, d: File<Diff>) -> SyncAction {
+        match self.remote_hash(&format!("/{}", d.path)).await {
+            Ok(Some(stored)) if stored != d.local_identity() => SyncAction::Conflict(d),
+            Ok(_) => {
+                info!("stored content of {} is missing or local, not a conflict", d.path);
+                SyncAction::Push(d)
+            }
+            Err(e) => {
+                warn!("cannot hash the stored content of {}: {:?}", d.path, e);
+                SyncAction::Conflict(d)
+            }
+        }
+    }
+
+    /// Plan and apply a sync of `dir`.
+    pub async fn sync(&mut self, dir: &str) -> Result<SyncReport, SyncError> {
+        let plan = self.plan_sync(dir).await?;
+        self.apply(plan).await
+    }
+
+    /// Execute a plan built by `plan_sync`. Failing actions don't stop the others, their errors
+    /// are collected in the report. Uploads run `jobs` at a time, sharing one progress bar.
+    pub async fn apply(&mut self, plan: SyncPlan) -> Result<SyncReport, SyncError> {
+        let mut base = self.load_state::<SyncBase>().map_err(|(p, e)| SyncError::IOError(p, e))?;
+        let mut report = SyncReport::default();
+        let replace = || Some(DownloadOptions::builder().overwrite(true).build());
+
+        let (uploads, actions): (Vec<_>, Vec<_>) = plan.actions.into_iter()
+            .partition(|a| matches!(a, SyncAction::Upload(_) | SyncAction::Push(_)));
+
+        let bar = progress_bar(uploads.iter().map(|a| match a {
+            SyncAction::Upload(l) => l.local_identity().size(),
+            SyncAction::Push(d) => d.local_identity().size(),
+            _ => 0
+        }).sum());
+        let bd = &*self;
+        let mut uploaded = stream::iter(uploads)
+            .map(|action| {
+                info!("{}", action);
+                let options = UploadOptions::builder()
+                    .overwrite(matches!(action, SyncAction::Push(_)))
+                    .progress(bar.clone())
+                    .build();
+                async move {
+                    match action {
+                        SyncAction::Upload(l) => bd.upload(l, Some(options)).await,
+                        SyncAction::Push(d) => bd.upload(d, Some(options)).await,
+                        _ => unreachable!("only uploads are queued")
+                    }
+                }
+            })
+            .buffer_unordered(self.jobs);
+        while let Some(synced) = uploaded.next().await {
+            match synced {
+                Ok(f) => {
+                    base.set(f.path(), f.identity());
+                    report.synced.push(f);
+                }
+                Err(e) => report.errors.push(SyncError::UploadError(Box::new(e)))
+            }
+        }
+        drop(uploaded);
+        bar.finish_and_clear();
+
+        for action in actions {
+            info!("{}", action);
+            // paths in the plan are relative to the local root, hence the leading `/`
+            let synced = match action {
+                SyncAction::Keep(f) => Ok(f),
+                SyncAction::Upload(_) | SyncAction::Push(_) => unreachable!("uploads are already done"),
+                SyncAction::Download(r) => self.download(&format!("/{}", r.path), None).await.map_err(|e| SyncError::DownloadError(Box::new(e))),
+                SyncAction::Pull(d) => self.download(&format!("/{}", d.path), replace()).await.map_err(|e| SyncError::DownloadError(Box::new(e))),
+                SyncAction::Conflict(d) => {
+                    report.conflicts.push(d);
+                    continue
+                }
+                SyncAction::DeleteLocal(l) => {
+                    match self.paths.to_local(&l.path).map_err(SyncError::PathError)
+                        .and_then(|p| std::fs::remove_file(p).map_err(|e| SyncError::IOError(l.path(), e))) {
+                        Ok(()) => {
+                            base.remove(&l.path);
+                            report.deleted.push(l.path);
+                        }
+                        Err(e) => report.errors.push(e)
+                    }
+                    continue
+                }
+                SyncAction::DeleteRemote(r) => {
+                    match self.delete(&format!("/{}", r.path)).await {
+                        Ok(r) => {
+                            base.remove(&r.path);
+                            report.deleted.push(r.path);
+                        }
+                        Err(e) => report.errors.push(SyncError::DeleteError(Box::new(e)))
+                    }
+                    continue
+                }
+            };
+            match synced {
+                Ok(f) => {
+                    base.set(f.path(), f.identity());
+                    report.synced.push(f);
+                }
+                Err(e) => report.errors.push(e)
+            }
+        }
+
+        self.save_state(&base).map_err(|(p, e)| SyncError::IOError(p, e))?;
+        if !report.deleted.is_empty() {
+            self.purge_expired().await;
+        }
+        Ok(report)
+    }
+}
+
+#[derive(Debug)]
+pub enum SyncError {
+    PathError(PathError),
+    IOError(String, std::io::Error),
+    DatabaseError(DatabaseError),
+    UploadError(Box<UploadError>),
+    DownloadError(Box<DownloadError>),
+    DeleteError(Box<DeleteError>)
+}
+
+impl From<PathError> for SyncError {
+    fn from(value: PathError) -> Self {
+        Self::PathError(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(hash: &str) -> Identity {
+        Identity::new(hash.to_string(), hash.len() as u64)
+    }
+
+    fn local(hash: &str) -> Option<File<LocalHashed>> {
+        Some(File::new("f".to_string(), LocalHashed::new(id(hash))))
+    }
+
+    fn remote(hash: &str) -> Option<File<Remote>> {
+        Some(File::new("f".to_string(), Remote { remote: id(hash) }))
+    }
+
+    fn tombstone(hash: &str) -> Tombstone {
+        Tombstone { path: "f".to_string(), hash: hash.to_string(), size: hash.len() as u64, deleted: 0, device: "other".to_string() }
+    }
+
+    /// Name of the action planned for a path with these sides, base and tombstone.
+    fn action(sides: Sides, base: Option<&str>, tombstone: Option<&Tombstone>) -> &'static str {
+        let base = base.map(id);
+        SyncClass::classify(sides.0, sides.1, base.as_ref()).unwrap().plan(base.as_ref(), tombstone).name()
+    }
+
+    #[test]
+    fn nothing_on_either_side() {
+        assert!(SyncClass::classify(None, None, Some(&id("a"))).is_none());
+    }
+
+    #[test]
+    fn both_sides() {
+        assert_eq!(action((local("a"), remote("a")), None, None), "keep");
+        assert_eq!(action((local("a"), remote("a")), Some("b"), None), "keep");
+        assert_eq!(action((local("b"), remote("a")), Some("a"), None), "push");
+        assert_eq!(action((local("a"), remote("b")), Some("a"), None), "pull");
+        assert_eq!(action((local("b"), remote("c")), Some("a"), None), "conflict");
+        assert_eq!(action((local("b"), remote("c")), None, None), "conflict");
+    }
+
+    #[test]
+    fn local_only() {
+        assert_eq!(action((local("a"), None), None, None), "upload");
+        // deleted remotely since the last sync, unless changed here meanwhile
+        assert_eq!(action((local("a"), None), Some("a"), None), "rm local");
+        assert_eq!(action((local("b"), None), Some("a"), None), "upload");
+        // never synced from here, the tombstone tells it was deleted elsewhere
+        assert_eq!(action((local("a"), None), None, Some(&tombstone("a"))), "rm local");
+        assert_eq!(action((local("b"), None), None, Some(&tombstone("a"))), "upload");
+        // the base wins over the tombstone
+        assert_eq!(action((local("b"), None), Some("c"), Some(&tombstone("b"))), "upload");
+    }
+
+    #[test]
+    fn tombstones() {
+        // only the very content deleted elsewhere, hash and size, is deleted here
+        let resized = Tombstone { size: 9, ..tombstone("a") };
+        assert_eq!(action((local("a"), None), None, Some(&resized)), "upload");
+        // a file on the remote is back, whatever was deleted before
+        assert_eq!(action((local("a"), remote("a")), None, Some(&tombstone("a"))), "keep");
+        assert_eq!(action((local("a"), remote("b")), None, Some(&tombstone("a"))), "conflict");
+        assert_eq!(action((None, remote("a")), None, Some(&tombstone("a"))), "download");
+
+        // one way syncs don't delete local files, push uploads them again
+        let plan = || SyncPlan { actions: vec![SyncClass::LocalOnly(local("a").unwrap()).plan(None, Some(&tombstone("a")))] };
+        let names = |p: SyncPlan| p.actions.iter().map(|a| a.name()).collect::<Vec<_>>();
+        assert_eq!(names(plan()), ["rm local"]);
+        assert_eq!(names(plan().push_only(false)), ["upload"]);
+        assert!(names(plan().pull_only(true)).is_empty());
+    }
+
+    #[test]
+    fn remote_only() {
+        assert_eq!(action((None, remote("a")), None, None), "download");
+        assert_eq!(action((None, remote("a")), Some("a"), None), "rm remote");
+        assert_eq!(action((None, remote("b")), Some("a"), None), "download");
+    }
+
+    #[test]
+    fn one_way_plans() {
+        let plan = |actions| SyncPlan { actions };
+        let diff = || match local("b").unwrap().attach_remote(remote("a").unwrap()) {
+            SyncState::Diff(d) => d,
+            SyncState::Sync(_) => unreachable!()
+        };
+        let names = |p: SyncPlan| p.actions.iter().map(|a| a.name()).collect::<Vec<_>>();
+        let actions = || vec![
+            SyncAction::Pull(diff()),
+            SyncAction::Push(diff()),
+            SyncAction::Conflict(diff()),
+            SyncAction::DeleteLocal(local("a").unwrap()),
+            SyncAction::Download(remote("a").unwrap())
+        ];
+        // a change on the other side only is left alone, unless overwritten
+        assert_eq!(names(plan(actions()).push_only(false)), ["push", "conflict", "upload"]);
+        assert_eq!(names(plan(actions()).push_only(true)), ["push", "push", "push", "upload"]);
+        assert_eq!(names(plan(actions()).pull_only(false)), ["pull", "conflict", "download"]);
+        assert_eq!(names(plan(actions()).pull_only(true)), ["pull", "pull", "pull", "download"]);
+    }
+}
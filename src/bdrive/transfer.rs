@@ -0,0 +1,216 @@
+use std::fmt::Write as FmtWrite;
+use std::collections::{HashMap, HashSet};
+use std::fs::File as StdFile;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Instant;
+use indicatif::{ProgressBar, ProgressState, ProgressStyle};
+use hex::ToHex;
+use log::{info, warn};
+use ring::digest::{digest, SHA256};
+use super::BDrive;
+use crate::db::Chunk;
+use crate::fs;
+use crate::fs::state::Identity;
+use crate::storage::{blob_key, partial_key, StorageError};
+
+const BUFF_SIZE: usize = 2 << 20;
+
+/// Blobs being stored or deleted right now. Concurrent uploads may share chunks, only one at a
+/// time stores a given blob and the others find it already there. An upload also pins the blobs
+/// it found or stored until its references to them are counted, so they aren't deleted meanwhile.
+#[derive(Debug, Default)]
+pub(crate) struct InFlight {
+    blobs: Mutex<Blobs>,
+    released: Condvar
+}
+
+#[derive(Debug, Default)]
+struct Blobs {
+    /// Claimed by one transfer at a time.
+    claimed: HashSet<String>,
+    /// How many uploads are about to reference each blob.
+    pinned: HashMap<String, usize>
+}
+
+/// Claim on a blob in flight, released when dropped.
+pub(crate) struct Claim<'a> {
+    in_flight: &'a InFlight,
+    blob: String
+}
+
+/// Pin on a blob an upload is about to reference, released when dropped.
+pub(crate) struct Pin {
+    in_flight: Arc<InFlight>,
+    blob: String
+}
+
+impl InFlight {
+    /// Claim `blob`, waiting until nobody else is storing or deleting it.
+    pub(crate) fn claim(&self, blob: &str) -> Claim<'_> {
+        let mut blobs = self.blobs.lock().unwrap();
+        while blobs.claimed.contains(blob) {
+            blobs = self.released.wait(blobs).unwrap();
+        }
+        blobs.claimed.insert(blob.to_string());
+        Claim { in_flight: self, blob: blob.to_string() }
+    }
+
+    /// Whether an upload is about to reference `blob`.
+    pub(crate) fn is_pinned(&self, blob: &str) -> bool {
+        self.blobs.lock().unwrap().pinned.contains_key(blob)
+    }
+}
+
+impl Claim<'_> {
+    /// Keep the blob from being deleted until the pin is dropped.
+    fn pin(&self, in_flight: &Arc<InFlight>) -> Pin {
+        *self.in_flight.blobs.lock().unwrap().pinned.entry(self.blob.clone()).or_default() += 1;
+        Pin { in_flight: in_flight.clone(), blob: self.blob.clone() }
+    }
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        self.in_flight.blobs.lock().unwrap().claimed.remove(&self.blob);
+        self.in_flight.released.notify_all();
+    }
+}
+
+impl Drop for Pin {
+    fn drop(&mut self) {
+        let mut blobs = self.in_flight.blobs.lock().unwrap();
+        if let Some(count) = blobs.pinned.get_mut(&self.blob) {
+            *count -= 1;
+            if *count == 0 {
+                blobs.pinned.remove(&self.blob);
+            }
+        }
+    }
+}
+
+pub(crate) fn progress_bar(size: u64) -> ProgressBar {
+    let bar = ProgressBar::new(size);
+    bar.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+        .unwrap()
+        .with_key("eta", |state: &ProgressState, w: &mut dyn FmtWrite| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
+        .progress_chars("#>-"));
+    bar
+}
+
+impl BDrive {
+    /// Split the local file `rel`, with content `id`, in chunks and copy to the storage the ones
+    /// it doesn't hold yet. Returns the chunks making up the file, and pins on their blobs to
+    /// hold until they're referenced.
+    /// The progress is added to `progress`, or shown on a bar of its own. It's also kept in the
+    /// upload journal, but every chunk is looked up in the storage anyway: a blob stored by an
+    /// interrupted attempt has no reference yet, so it may have been collected since.
+    pub(crate) fn send(&self, rel: &str, id: &Identity, progress: Option<&ProgressBar>) -> Result<(Vec<Chunk>, Vec<Pin>), StorageError> {
+        let path = self.paths.absolute(rel)?;
+        info!("uploading file {}", rel);
+
+        let (mut journal, resumed) = self.journal_upload(rel, &id.hash());
+        if resumed > 0 {
+            info!("resuming upload of {}, {} bytes were already stored", rel, resumed);
+        }
+
+        let local_reader = BufReader::with_capacity(BUFF_SIZE, StdFile::open(path)?);
+        let own_bar;
+        let bar = match progress {
+            Some(b) => b,
+            None => {
+                own_bar = progress_bar(id.size());
+                &own_bar
+            }
+        };
+
+        let compress = self.compresses(rel);
+        let start = Instant::now();
+        let mut chunks = vec![];
+        let mut pins = vec![];
+        let mut sent = 0;
+        for c in fs::chunks(local_reader) {
+            let (chunk, data) = c?;
+            let (chunk, data) = self.encode(chunk, data, compress);
+            {
+                let claim = self.in_flight.claim(chunk.blob());
+                pins.push(claim.pin(&self.in_flight));
+                let key = blob_key(chunk.blob());
+                match self.storage.stat(&key)? {
+                    Some(size) if size == chunk.stored_size() => {},
+                    stored => {
+                        if stored.is_some() {
+                            warn!("stored blob {} has the wrong size, replacing it", chunk.blob());
+                            self.storage.delete(&key)?;
+                        }
+                        sent += self.put_blob(&chunk, &data)?;
+                    }
+                }
+            }
+            journal.stored(chunk.blob(), chunk.size);
+            bar.inc(chunk.size);
+            chunks.push(chunk);
+        }
+        if progress.is_none() {
+            bar.finish();
+        }
+
+        journal.finish();
+        info!("sent {} of {} bytes, operation took {:?}", sent, id.size(), start.elapsed());
+        Ok((chunks, pins))
+    }
+
+    /// Store `data` as the blob `chunk`. It's appended to a partial key, so an interruption
+    /// leaves what was sent, and moved in place once complete and hashed back to the name of the
+    /// blob: a damaged partial blob is never promoted. If a previous attempt left a partial blob
+    /// whose content matches the start of `data`, only the rest is sent. Returns the number of
+    /// bytes sent.
+    fn put_blob(&self, chunk: &Chunk, data: &[u8]) -> Result<u64, StorageError> {
+        let tmp = partial_key(chunk.blob());
+        let mut offset = 0;
+        if let Some(len) = self.storage.stat(&tmp)? {
+            let len = len as usize;
+            let prefix: Option<String> = data.get(..len).map(|p| digest(&SHA256, p).encode_hex());
+            if len > 0 && prefix.is_some() && self.storage.hash(&tmp)? == prefix {
+                info!("resuming blob {} from byte {}", chunk.blob(), len);
+                offset = len;
+            } else {
+                warn!("partial blob {} doesn't match, sending it again", chunk.blob());
+                self.storage.delete(&tmp)?;
+            }
+        }
+
+        self.storage.append(&tmp, &mut &data[offset..])?;
+        if self.storage.stat(&tmp)? != Some(chunk.stored_size())
+            || self.storage.hash(&tmp)?.as_deref() != Some(chunk.blob()) {
+            self.storage.delete(&tmp)?;
+            return Err(StorageError::Incomplete(tmp))
+        }
+        self.storage.rename(&tmp, &blob_key(chunk.blob()))?;
+        Ok((data.len() - offset) as u64)
+    }
+
+    /// Join the stored `chunks` into `dest`, which is created or truncated.
+    pub(crate) fn receive(&self, chunks: &[Chunk], dest: &Path, size: u64) -> Result<(), StorageError> {
+        info!("downloading {} chunks to {:?}", chunks.len(), dest);
+
+        let local_writer = BufWriter::with_capacity(BUFF_SIZE, StdFile::create(dest)?);
+        let bar = progress_bar(size);
+        let mut bar_writer = bar.wrap_write(local_writer);
+
+        let start = Instant::now();
+        for chunk in chunks {
+            if chunk.is_plain() {
+                self.storage.get(&blob_key(chunk.blob()), &mut bar_writer)?;
+            } else {
+                let mut data = Vec::with_capacity(chunk.stored_size() as usize);
+                self.storage.get(&blob_key(chunk.blob()), &mut data)?;
+                bar_writer.write_all(&self.decode(chunk, data)?)?;
+            }
+        }
+        bar_writer.flush()?;
+        info!("operation took {:?}", start.elapsed());
+        Ok(())
+    }
+}
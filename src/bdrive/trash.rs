@@ -0,0 +1,180 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::{info, warn};
+use super::{BDrive, BlobError};
+use crate::db::{Chunk, DatabaseError, Trashed};
+use crate::fs::{File, state::*};
+use crate::storage::StorageError;
+
+impl BDrive {
+    /// Deleted files inside `path` that can still be restored, ordered by path and deletion.
+    pub async fn trash(&self, path: &str) -> Result<Vec<Trashed>, TrashError> {
+        Ok(self.db.trash(&self.canonicalize_prefix(path)).await?)
+    }
+
+    /// Put the deleted file `path` back, the last one deleted there unless `number` is given.
+    /// The file must not exist again meanwhile. Only the database changes: the local copy comes
+    /// back with the next sync.
+    pub async fn restore_trashed(&self, path: &str, number: Option<u64>) -> Result<File<Remote>, TrashError> {
+        let path = self.canonicalize(path);
+        let trashed = self.db.trash(&path).await?.into_iter()
+            .rfind(|t| t.path == path && number.is_none_or(|n| t.number == n));
+        let trashed = match trashed {
+            Some(t) => t,
+            None => return Err(TrashError::NotFound(path))
+        };
+
+        let restored = File::new(path.clone(), Remote { remote: Identity::new(trashed.hash.clone(), trashed.size) });
+        self.ref_content(&trashed.chunks).await?;
+        if let Err(e) = self.db.create_remote(&restored, trashed.chunks.clone()).await {
+            if let Err(e) = self.release_content(&trashed.chunks).await {
+                warn!("cannot release chunks: {:?}", e);
+            }
+            return Err(match e {
+                DatabaseError::Duplicate(p) => TrashError::Exists(p),
+                e => TrashError::DatabaseError(e)
+            })
+        }
+        info!("restored {} from the trash", path);
+        if let Err(e) = self.drop_trashed(&trashed).await {
+            warn!("cannot remove {} from the trash: {:?}", path, e);
+        }
+        Ok(restored)
+    }
+
+    /// Remove the deleted files older than the configured period from the trash, or all of them,
+    /// freeing their content. The versions of a file go once nothing is left of it.
+    pub async fn purge_trash(&self, all: bool) -> Result<Vec<Trashed>, TrashError> {
+        let expiry = (!all).then(|| now().saturating_sub(self.trash.days * 86400));
+        let trash = self.db.trash("").await?;
+        let mut purged = vec![];
+        for (t, last) in expired(&trash, expiry) {
+            self.drop_trashed(t).await?;
+            if last && !self.db.exists(&t.path).await? {
+                self.forget_versions(&t.path).await?;
+            }
+            purged.push(t.clone());
+        }
+        Ok(purged)
+    }
+
+    /// Purge what expired, at the end of a command that deleted files: a failure is only logged.
+    pub async fn purge_expired(&self) {
+        match self.purge_trash(false).await {
+            Ok(purged) if !purged.is_empty() => info!("purged {} files from the trash", purged.len()),
+            Ok(_) => {},
+            Err(e) => warn!("cannot purge the trash: {:?}", e)
+        }
+    }
+
+    /// Add the file `remote` made of `chunks` to the trash, with its own references to them.
+    pub(crate) async fn add_trash(&self, remote: &File<Remote>, chunks: &[Chunk]) -> Result<Trashed, DatabaseError> {
+        let path = remote.path.clone();
+        let number = self.db.trash(&path).await?.iter()
+            .filter(|t| t.path == path)
+            .map(|t| t.number)
+            .max()
+            .unwrap_or(0) + 1;
+        let id = remote.remote_identity();
+        let trashed = Trashed {
+            path,
+            number,
+            hash: id.hash(),
+            size: id.size(),
+            chunks: chunks.to_vec(),
+            deleted: now(),
+            device: self.device.clone()
+        };
+        self.ref_content(chunks).await?;
+        if let Err(e) = self.db.add_trash(trashed.clone()).await {
+            if let Err(e) = self.release_content(chunks).await {
+                warn!("cannot release chunks: {:?}", e);
+            }
+            return Err(e)
+        }
+        Ok(trashed)
+    }
+
+    /// Remove an entry from the trash and release its content.
+    pub(crate) async fn drop_trashed(&self, trashed: &Trashed) -> Result<(), BlobError> {
+        if self.db.delete_trash(&trashed.path, trashed.number).await? {
+            self.release_content(&trashed.chunks).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Entries of `trash`, ordered by path and deletion, deleted at `expiry` or before, or all of
+/// them without one. Each comes with whether nothing of its path is left in the trash after it.
+fn expired(trash: &[Trashed], expiry: Option<u64>) -> Vec<(&Trashed, bool)> {
+    trash.iter().enumerate()
+        .filter(|(_, t)| expiry.is_none_or(|e| t.deleted <= e))
+        .map(|(i, t)| (t, !trash[i + 1..].iter().any(|r| r.path == t.path)))
+        .collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+#[derive(Debug)]
+pub enum TrashError {
+    NotFound(String),
+    /// A file was created where the deleted one would be restored.
+    Exists(String),
+    StorageError(StorageError),
+    DatabaseError(DatabaseError)
+}
+
+impl From<DatabaseError> for TrashError {
+    fn from(value: DatabaseError) -> Self {
+        TrashError::DatabaseError(value)
+    }
+}
+
+impl From<BlobError> for TrashError {
+    fn from(value: BlobError) -> Self {
+        match value {
+            BlobError::StorageError(e) => TrashError::StorageError(e),
+            BlobError::DatabaseError(e) => TrashError::DatabaseError(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trashed(path: &str, number: u64, deleted: u64) -> Trashed {
+        Trashed {
+            path: path.to_string(),
+            number,
+            hash: "h".to_string(),
+            size: 4,
+            chunks: vec![],
+            deleted,
+            device: "test".to_string()
+        }
+    }
+
+    fn names(purged: Vec<(&Trashed, bool)>) -> Vec<(String, u64, bool)> {
+        purged.into_iter().map(|(t, last)| (t.path.clone(), t.number, last)).collect()
+    }
+
+    #[test]
+    fn expiry() {
+        let trash = [trashed("a", 1, 10), trashed("a", 2, 30), trashed("b", 1, 20), trashed("c", 1, 40)];
+        assert_eq!(names(expired(&trash, Some(20))), [
+            ("a".to_string(), 1, false),
+            ("b".to_string(), 1, true)
+        ]);
+        // a path is gone from the trash once its newest entry is purged
+        assert_eq!(names(expired(&trash, Some(30))), [
+            ("a".to_string(), 1, false),
+            ("a".to_string(), 2, true),
+            ("b".to_string(), 1, true)
+        ]);
+        assert_eq!(expired(&trash, None).len(), 4);
+        assert!(expired(&trash, Some(9)).is_empty());
+        assert!(expired(&[], None).is_empty());
+    }
+}
@@ -1,15 +1,20 @@
-use std::fmt::Debug;
-use super::BDrive;
-use crate::fs::{Upload, File, state::*, FileSuccess, SyncState, Split, LocalFile};
-use crate::ssh::SSHError;
+use super::{BDrive, BlobError};
+use crate::fs::{Upload, File, state::*, FileSuccess, SyncState, Split};
+use crate::storage::StorageError;
+use log::{debug, info, warn};
+use indicatif::ProgressBar;
+use crate::db::{Chunk, DatabaseError};
 
 impl BDrive {
-    /// This function tries upload a file to the remote server via ssh.
+    /// This function tries upload a file to the remote storage.
+    /// The content is split in chunks, each stored once per hash: identical files share the same
+    /// blobs and a modified file only sends the chunks that changed. Every upload is also
+    /// recorded as a new version, which keeps the content it replaces available.
     /// If it succeeds then it tries to updates the remote database with the changes.
-    /// If it fails the remote file is deleted and an UploadError is returned.
-    pub async fn upload<'a>(&mut self, file: impl Upload + Debug + Sized + 'a, options: Option<UploadOptions>) -> Result<File<Sync>, UploadError> {
-        let options = if let Some(o) = options { o } else { UploadOptions::default() };
-        println!("local file before searching {:?}", file);
+    /// If it fails the reference to the blob is released and an UploadError is returned.
+    pub async fn upload<'a>(&self, file: impl Upload + Sized + 'a, options: Option<UploadOptions>) -> Result<File<Sync>, UploadError> {
+        let options = options.unwrap_or_default();
+        debug!("local file before searching {:?}", file);
         // println!("search result: {:?}", self.get_file_file(file).await);
 
         match self.db.get_file_file(file).await {
@@ -17,21 +22,36 @@ impl BDrive {
                 FileSuccess::Yes(st) => {
                     match st {
                         SyncState::Sync(f) => {
-                            println!("file is online and synced");
+                            info!("file is online and synced");
                             Ok(f)
                         },
                         SyncState::Diff(f) => {
-                            println!("file is uploaded but not in sync");
+                            info!("file is uploaded but not in sync");
                             if options.overwrite {
-                                println!("overwriting remote file");
-                                if let Err(e) = self.ssh.write(&self.paths, f.path(), f.size()) {
-                                    return Err(UploadError::SSHError(f.downcast(), e))
-                                }
+                                info!("overwriting remote file");
+                                let old = match self.chunks(&f.path).await {
+                                    Ok(c) => c,
+                                    Err(e) => return Err(UploadError::DatabaseError(f.downcast(), e))
+                                };
+                                let chunks = match self.store_content(&f.path, &f.local_identity(), options.progress.clone()).await {
+                                    Ok(c) => c,
+                                    Err(e) => return Err(UploadError::from_blob(f.downcast(), e))
+                                };
                                 // upload ok, update database.
-                                print!("file uploaded, trying to update database...");
-                                match self.db.push(f).await {
-                                    FileSuccess::Yes(f) => { println!("Ok, done"); Ok(f) },
-                                    FileSuccess::No(e, o) => Err(UploadError::MongoDBError(self.clean_storage(o).await, e))
+                                info!("file uploaded, trying to update database...");
+                                match self.db.push(f, chunks.clone()).await {
+                                    FileSuccess::Yes(f) => {
+                                        info!("database updated");
+                                        if let Err(e) = self.release_content(&old).await {
+                                            warn!("cannot release previous content of {}: {:?}", f.path, e);
+                                        }
+                                        self.record_version(&f.path, &f.identity(), &chunks).await;
+                                        Ok(f)
+                                    },
+                                    FileSuccess::No(e, o) => {
+                                        warn!("cannot update database, {} keeps its previous content", o.path);
+                                        Err(UploadError::DatabaseError(self.clean_storage(o.downcast(), &chunks).await, e))
+                                    }
                                 }
                             } else {
                                 let (local, remote) = f.split();
@@ -41,39 +61,45 @@ impl BDrive {
                     }
                 }
                 FileSuccess::No((), o) => {
-                    println!("cannot find file remotely, creating new one.");
-                    if let Err(e) = self.ssh.write(&self.paths, o.path(), o.size()) {
-                        Err(UploadError::SSHError(o.downcast(), e))
-                    } else {
-                        println!("upload success, creating file in db.");
-                        match self.db.create(o).await {
-                            FileSuccess::Yes(s) => Ok(s),
-                            FileSuccess::No(e, o) => Err(UploadError::MongoDBError(o.downcast(), e))
+                    info!("cannot find file remotely, creating new one.");
+                    match self.store_content(&o.path(), &o.local_identity(), options.progress.clone()).await {
+                        Err(e) => Err(UploadError::from_blob(o.downcast(), e)),
+                        Ok(chunks) => {
+                            info!("upload success, creating file in db.");
+                            match self.db.create(o, chunks.clone()).await {
+                                FileSuccess::Yes(s) => {
+                                    self.record_version(&s.path, &s.identity(), &chunks).await;
+                                    Ok(s)
+                                }
+                                FileSuccess::No(e, o) => Err(UploadError::DatabaseError(self.clean_storage(o.downcast(), &chunks).await, e))
+                            }
                         }
                     }
                 }
             }
-            Err((e, f)) => Err(UploadError::MongoDBError(f.downcast(), e))
+            Err((e, f)) => Err(UploadError::DatabaseError(f.downcast(), e))
         }
     }
 
-    async fn clean_storage(&self, f: File<Diff>) -> File<LocalHashed> {
-        // todo: actually we should check if the file existed, if so, restore the original
-        println!("deleting {}, since it cannot be added to database.", f.path);
-        match self.ssh.delete(f.path()) {
-            Ok(_) => f.downcast(),
-            Err(_e) => {
-                // todo: if this fails add an entry to database, when possible clean dangling files
-                f.downcast();
-                todo!()
-            }
+    /// Undo the storage side of a failed upload. Blobs are never written in place and the old
+    /// content is only released after the database points to the new one, so the previous version
+    /// is still there: only the references taken for `chunks` have to be dropped.
+    /// If that fails too the new blobs are left behind, recorded in the dangling journal for the
+    /// garbage collector: it wastes space but loses nothing.
+    async fn clean_storage(&self, f: File<LocalHashed>, chunks: &[Chunk]) -> File<LocalHashed> {
+        warn!("releasing content of {}, since it cannot be added to database.", f.path);
+        if let Err(e) = self.release_content(chunks).await {
+            warn!("cannot release content of {}, its blobs are left in the storage: {:?}", f.path, e);
         }
+        f
     }
 }
 
 #[derive(Clone, Default)]
 pub struct UploadOptions {
-    pub overwrite: bool
+    pub overwrite: bool,
+    /// Bar where the transfer adds its progress, instead of showing one of its own.
+    pub progress: Option<ProgressBar>
 }
 
 pub struct UploadOptionsBuilder {
@@ -92,6 +118,11 @@ impl UploadOptionsBuilder {
         self
     }
 
+    pub fn progress(mut self, progress: ProgressBar) -> UploadOptionsBuilder {
+        self.inner.progress = Some(progress);
+        self
+    }
+
     pub fn build(self) -> UploadOptions {
         self.inner
     }
@@ -100,7 +131,16 @@ impl UploadOptionsBuilder {
 #[derive(Debug)]
 pub enum UploadError {
     OverwriteError(File<LocalHashed>, File<Remote>),
-    SSHError(File<LocalHashed>, SSHError),
-    MongoDBError(File<LocalHashed>, mongodb::error::Error)
+    StorageError(File<LocalHashed>, StorageError),
+    DatabaseError(File<LocalHashed>, DatabaseError)
+}
+
+impl UploadError {
+    fn from_blob(f: File<LocalHashed>, e: BlobError) -> Self {
+        match e {
+            BlobError::StorageError(e) => Self::StorageError(f, e),
+            BlobError::DatabaseError(e) => Self::DatabaseError(f, e)
+        }
+    }
 }
 
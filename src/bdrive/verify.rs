@@ -0,0 +1,114 @@
+use log::info;
+use super::{BDrive, RemoteHashError};
+use crate::crypto::CryptoError;
+use crate::fs::{File, state::*};
+use crate::storage::{blob_key, StorageError};
+use crate::db::{Chunk, DatabaseError};
+
+/// Outcome of checking a database record against the remote storage.
+#[derive(Debug)]
+pub enum Verification {
+    Ok(File<Remote>),
+    Missing(File<Remote>),
+    /// The remote content exists but its size, here included, differs from the recorded one.
+    SizeMismatch(File<Remote>, u64),
+    /// The remote content has the right size but its hash, here included, differs from the
+    /// recorded one. For encrypted files it's the hash of the blob that doesn't decrypt.
+    Corrupt(File<Remote>, String)
+}
+
+impl BDrive {
+    /// Check that every chunk of the files recorded in the database under `path` exists in the
+    /// remote storage with the expected size. With the `rehash` option the stored content is
+    /// also hashed, like [`BDrive::remote_hash`] does, and compared with the recorded hash.
+    pub async fn verify(&self, path: &str, options: Option<VerifyOptions>) -> Result<Vec<Verification>, VerifyError> {
+        let options = options.unwrap_or_default();
+        let mut checked = vec![];
+        for r in self.db.list(&self.canonicalize_prefix(path)).await.map_err(VerifyError::DatabaseError)? {
+            let chunks = self.chunks(&r.path).await.map_err(VerifyError::DatabaseError)?;
+            let stored = chunks.clone();
+            checked.push(match self.off_runtime(move |bd| bd.check(r, &stored)).await? {
+                Verification::Ok(r) if options.rehash => self.rehash(r, &chunks).await?,
+                v => v
+            });
+        }
+        Ok(checked)
+    }
+
+    /// Compare the identity of the stored content of `r`, made of `chunks`, with the recorded one.
+    async fn rehash(&self, r: File<Remote>, chunks: &[Chunk]) -> Result<Verification, VerifyError> {
+        info!("hashing {}", r.path);
+        let recorded = r.remote_identity();
+        Ok(match self.remote_hash(&format!("/{}", r.path)).await {
+            Ok(None) => Verification::Missing(r),
+            Ok(Some(id)) if id.hash() != recorded.hash() => Verification::Corrupt(r, id.hash()),
+            Ok(Some(_)) => Verification::Ok(r),
+            Err(RemoteHashError::StorageError(StorageError::Crypto(CryptoError::Decrypt(h)))) => {
+                // the hash of what's stored tells the damaged blob from its name
+                let key = blob_key(chunks.iter().find(|c| c.hash == h).map(|c| c.blob()).unwrap_or(&h));
+                match self.on_storage(move |s| s.hash(&key)).await {
+                    Ok(found) => Verification::Corrupt(r, found.unwrap_or(h)),
+                    Err(e) => return Err(VerifyError::StorageError(r, e))
+                }
+            }
+            Err(RemoteHashError::StorageError(e)) => return Err(VerifyError::StorageError(r, e)),
+            Err(RemoteHashError::DatabaseError(e)) => return Err(VerifyError::DatabaseError(e))
+        })
+    }
+
+    /// Check the stored `chunks` of the record `r`, it blocks until the storage answers.
+    fn check(&self, r: File<Remote>, chunks: &[Chunk]) -> Result<Verification, VerifyError> {
+        let mut missing = false;
+        let mut mismatch = false;
+        let mut stored = 0;
+        for chunk in chunks {
+            match self.storage.stat(&blob_key(chunk.blob())) {
+                Ok(None) => missing = true,
+                Ok(Some(size)) => {
+                    mismatch |= size != chunk.stored_size();
+                    stored += size;
+                }
+                Err(e) => return Err(VerifyError::StorageError(r, e))
+            }
+        }
+        Ok(if missing {
+            Verification::Missing(r)
+        } else if mismatch {
+            Verification::SizeMismatch(r, stored)
+        } else {
+            Verification::Ok(r)
+        })
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct VerifyOptions {
+    pub rehash: bool
+}
+
+pub struct VerifyOptionsBuilder {
+    inner: VerifyOptions
+}
+
+impl VerifyOptions {
+    pub fn builder() -> VerifyOptionsBuilder {
+        VerifyOptionsBuilder { inner: Self::default() }
+    }
+}
+
+impl VerifyOptionsBuilder {
+    pub fn rehash(mut self, rehash: bool) -> VerifyOptionsBuilder {
+        self.inner.rehash = rehash;
+        self
+    }
+
+    pub fn build(self) -> VerifyOptions {
+        self.inner
+    }
+}
+
+#[derive(Debug)]
+pub enum VerifyError {
+    StorageError(File<Remote>, StorageError),
+    DatabaseError(DatabaseError)
+}
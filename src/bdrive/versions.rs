@@ -0,0 +1,109 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::{info, warn};
+use super::{BDrive, BlobError};
+use crate::db::{Chunk, DatabaseError, Version};
+use crate::fs::{File, state::*};
+
+impl BDrive {
+    /// Versions of the remote file `path`, oldest first. The last one is the current content.
+    pub async fn versions(&self, path: &str) -> Result<Vec<Version>, VersionError> {
+        let path = self.canonicalize(path);
+        let versions = match self.db.versions(&path).await {
+            Ok(v) => v,
+            Err(e) => return Err(VersionError::DatabaseError(path, e))
+        };
+        if versions.is_empty() {
+            match self.db.exists(&path).await {
+                Ok(false) => return Err(VersionError::NotFound(path)),
+                Ok(true) => {},
+                Err(e) => return Err(VersionError::DatabaseError(path, e))
+            }
+        }
+        Ok(versions)
+    }
+
+    /// Make version `number` of the remote file `path` its current content again, recorded as
+    /// the newest version. Only the database changes, the content is already stored: the local
+    /// copy is updated by the next pull.
+    pub async fn restore(&self, path: &str, number: u64) -> Result<File<Remote>, VersionError> {
+        let path = self.canonicalize(path);
+        let version = match self.versions(&path).await?.into_iter().find(|v| v.number == number) {
+            Some(v) => v,
+            None => return Err(VersionError::NoVersion(path, number))
+        };
+        let restored = match self.replace_content(&path, Identity::new(version.hash, version.size), &version.chunks).await {
+            Ok(r) => r,
+            Err(e) => return Err(VersionError::DatabaseError(path, e))
+        };
+        info!("restored version {} of {}", number, path);
+        Ok(restored)
+    }
+
+    /// Make `chunks` the content of the remote file `path`, creating it if needed, and record it
+    /// as a new version. The previous content is released.
+    pub(crate) async fn replace_content(&self, path: &str, id: Identity, chunks: &[Chunk]) -> Result<File<Remote>, DatabaseError> {
+        let old = self.chunks(path).await?;
+        let replaced = File::new(path.to_string(), Remote { remote: id.clone() });
+        self.ref_content(chunks).await?;
+        if let Err(e) = self.db.replace_remote(&replaced, chunks.to_vec()).await {
+            warn!("cannot replace {}, it keeps its current content", path);
+            if let Err(e) = self.release_content(chunks).await {
+                warn!("cannot release chunks: {:?}", e);
+            }
+            return Err(e)
+        }
+        if let Err(e) = self.release_content(&old).await {
+            warn!("cannot release previous content of {}: {:?}", path, e);
+        }
+        self.record_version(path, &id, chunks).await;
+        Ok(replaced)
+    }
+
+    /// Add a version of `path` with content `id` made of `chunks`, holding its own references to
+    /// them. The file record is already written, so a failure only leaves a gap in the history
+    /// and is logged.
+    pub(crate) async fn record_version(&self, path: &str, id: &Identity, chunks: &[Chunk]) {
+        if let Err(e) = self.add_version(path, id, chunks).await {
+            warn!("cannot record a version of {}: {:?}", path, e);
+        }
+    }
+
+    async fn add_version(&self, path: &str, id: &Identity, chunks: &[Chunk]) -> Result<(), DatabaseError> {
+        let number = self.db.versions(path).await?.last().map(|v| v.number).unwrap_or(0) + 1;
+        self.ref_content(chunks).await?;
+        let version = Version {
+            path: path.to_string(),
+            number,
+            hash: id.hash(),
+            size: id.size(),
+            chunks: chunks.to_vec(),
+            time: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+            device: self.device.clone()
+        };
+        if let Err(e) = self.db.add_version(version).await {
+            if let Err(e) = self.release_content(chunks).await {
+                warn!("cannot release chunks: {:?}", e);
+            }
+            return Err(e)
+        }
+        Ok(())
+    }
+
+    /// Remove every version of `path` and release their content, once the file itself is gone.
+    pub(crate) async fn forget_versions(&self, path: &str) -> Result<(), BlobError> {
+        for v in self.db.versions(path).await? {
+            if self.db.delete_version(path, v.number).await? {
+                self.release_content(&v.chunks).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum VersionError {
+    NotFound(String),
+    /// The file exists but has no version with this number.
+    NoVersion(String, u64),
+    DatabaseError(String, DatabaseError)
+}
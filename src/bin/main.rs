@@ -1,35 +1,509 @@
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, SystemTime};
+use clap::{ArgAction, Parser, Subcommand};
+use log::LevelFilter;
+use bdrive::bdrive::{BDrive, FileStatus, SyncPlan, Verification, VerifyOptions, STATE_DIR};
+use bdrive::conf::Configs;
+use bdrive::fs::LocalFile;
+
+#[derive(Parser)]
+#[command(version, about = "Keep a local tree in sync with a remote store")]
+struct Cli {
+    /// Configuration file
+    #[arg(short, long, global = true, default_value = "config.toml")]
+    config: PathBuf,
+    /// Print more details, repeat for even more
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+    #[command(subcommand)]
+    command: Command
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Write a configuration file for syncing the current directory
+    Init {
+        /// Replace an existing configuration file
+        #[arg(long)]
+        force: bool
+    },
+    /// Upload new and changed local files
+    Push {
+        #[arg(default_value = ".")]
+        path: String,
+        /// Also replace remote files that changed on the remote
+        #[arg(long)]
+        overwrite: bool,
+        /// Only show what would be done
+        #[arg(short = 'n', long)]
+        dry_run: bool
+    },
+    /// Download new and changed remote files
+    Pull {
+        #[arg(default_value = ".")]
+        path: String,
+        /// Also replace local files that changed locally
+        #[arg(long)]
+        overwrite: bool,
+        /// Only show what would be done
+        #[arg(short = 'n', long)]
+        dry_run: bool
+    },
+    /// Upload, download and delete files until both sides match
+    Sync {
+        #[arg(default_value = ".")]
+        path: String,
+        /// Only show what would be done
+        #[arg(short = 'n', long)]
+        dry_run: bool
+    },
+    /// Show which files are new, modified, synced, remote only or ignored
+    Status {
+        #[arg(default_value = ".")]
+        path: String,
+        /// Print the full report as JSON
+        #[arg(long)]
+        json: bool
+    },
+    /// List remote files
+    Ls {
+        #[arg(default_value = ".")]
+        path: String
+    },
+    /// Delete files from the remote and the local tree
+    Rm {
+        #[arg(required = true)]
+        paths: Vec<String>,
+        /// Only delete the remote copy
+        #[arg(long)]
+        keep_local: bool,
+        /// Also delete local copies with changes that were never uploaded
+        #[arg(short, long)]
+        force: bool
+    },
+    /// Move a file, both on the remote and in the local tree
+    Mv {
+        from: String,
+        to: String
+    },
+    /// Copy a file, the copy shares the stored content of the original
+    Cp {
+        from: String,
+        to: String
+    },
+    /// List the versions of a remote file, the last one is the current content
+    Versions {
+        path: String
+    },
+    /// Make a previous version of a remote file its current content, pull to update the local copy
+    Restore {
+        path: String,
+        /// Number of the version, as listed by `versions`
+        version: u64
+    },
+    /// Record, list and restore the state of whole directories
+    Snapshot {
+        #[command(subcommand)]
+        command: SnapshotCommand
+    },
+    /// List, restore and purge deleted files
+    Trash {
+        #[command(subcommand)]
+        command: TrashCommand
+    },
+    /// Remove the versions and snapshots the retention policy doesn't keep
+    Prune {
+        /// Only show what would be removed and how much it would free
+        #[arg(short = 'n', long)]
+        dry_run: bool
+    },
+    /// Remove stored content no file uses and fix the reference counts
+    Gc {
+        /// Only show what would be done
+        #[arg(short = 'n', long)]
+        dry_run: bool
+    },
+    /// Move contents stored by older versions at the path of their file to blobs
+    Migrate {
+        /// Only show what would be done
+        #[arg(short = 'n', long)]
+        dry_run: bool
+    },
+    /// Check that the remote files match the database
+    Verify {
+        #[arg(default_value = ".")]
+        path: String,
+        /// Also hash the stored content, reading all of it
+        #[arg(long)]
+        rehash: bool
+    }
+}
+
+#[derive(Subcommand)]
+enum SnapshotCommand {
+    /// Record the remote files inside a directory as they are now
+    Create {
+        #[arg(default_value = ".")]
+        path: String,
+        /// Name of the snapshot, the current time if not given
+        #[arg(long)]
+        name: Option<String>
+    },
+    /// List the snapshots, oldest first
+    List,
+    /// Bring the remote files back to a snapshot, sync to update the local tree
+    Restore {
+        name: String,
+        /// Download the files into this directory instead, leaving the remote as it is
+        #[arg(long)]
+        into: Option<PathBuf>
+    },
+    /// Remove a snapshot, its content is freed unless something else uses it
+    Delete {
+        name: String
+    }
+}
+
+#[derive(Subcommand)]
+enum TrashCommand {
+    /// List the deleted files that can be restored
+    List {
+        #[arg(default_value = ".")]
+        path: String
+    },
+    /// Put a deleted file back on the remote, sync to get the local copy
+    Restore {
+        path: String,
+        /// Which deletion of the path, as listed by `trash list`, the last one if not given
+        #[arg(long)]
+        number: Option<u64>
+    },
+    /// Remove the expired files from the trash for good
+    Purge {
+        /// Remove every file, expired or not
+        #[arg(long)]
+        all: bool
+    }
+}
+
 #[tokio::main]
-async fn main() -> mongodb::error::Result<()> {
-    use std::fs::{read_to_string};
-    use bdrive::bdrive::UploadOptions;
-    use bdrive::conf::Configs;
-    use bdrive::bdrive::BDrive;
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
 
-    println!("creating bdrive...");
+    env_logger::Builder::new()
+        .filter_level(match cli.verbose {
+            0 => LevelFilter::Warn,
+            1 => LevelFilter::Info,
+            2 => LevelFilter::Debug,
+            _ => LevelFilter::Trace
+        })
+        .parse_default_env()
+        .init();
 
-    let configs: Configs = toml::from_str(&read_to_string("config.toml")?).expect("AAAAAAAAH");
-    println!("{:?}", configs);
+    if let Command::Init { force } = cli.command {
+        return report(init(&cli.config, force))
+    }
+
+    let configs = match std::fs::read_to_string(&cli.config) {
+        Ok(s) => match toml::from_str::<Configs>(&s) {
+            Ok(c) => c,
+            Err(e) => return report(Err(format!("invalid configuration {:?}: {}", cli.config, e)))
+        },
+        Err(e) => return report(Err(format!("cannot read configuration {:?}: {}", cli.config, e)))
+    };
 
-    let mut bd = BDrive::new(configs).await?;
+    let mut bd = match BDrive::new(configs).await {
+        Ok(bd) => bd,
+        Err(e) => return report(Err(format!("cannot connect: {:?}", e)))
+    };
 
-    let overwrite = Some(UploadOptions::builder().overwrite(true).build());
+    report(run(&mut bd, cli.command).await)
+}
 
-    // upload this code for testing out the scan dir
-    for f in bd.scan_dir("src").unwrap() {
-        println!("=> Found: {:?}", f);
-        match f {
-            Ok(f) => {
-                println!("=> Is file, hashing it...");
-                let f = f.hash().unwrap();
-                println!("=> and then upload it!");
-                println!("=> DONE! (result: {:?})", bd.upload(f, overwrite.clone()).await);
+fn report(result: Result<(), String>) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn fail(e: impl Debug) -> String {
+    format!("{:?}", e)
+}
+
+fn init(config: &Path, force: bool) -> Result<(), String> {
+    if config.exists() && !force {
+        return Err(format!("{:?} already exists, use --force to replace it", config))
+    }
+    let local = std::env::current_dir().and_then(|d| d.canonicalize()).map_err(fail)?;
+    let mut template = Configs::template(local.to_str().unwrap().to_string());
+    if config.is_relative() {
+        // the configuration holds credentials, it must never be uploaded
+        template.paths.ignore.push(format!("/{}", config.strip_prefix(".").unwrap_or(config).to_str().unwrap()));
+    }
+    std::fs::write(config, toml::to_string(&template).map_err(fail)?).map_err(fail)?;
+    std::fs::create_dir_all(local.join(STATE_DIR)).map_err(fail)?;
+    println!("written {:?}, fill in the server details before using it", config);
+    Ok(())
+}
+
+async fn run(bd: &mut BDrive, command: Command) -> Result<(), String> {
+    match command {
+        Command::Init { .. } => unreachable!(),
+        Command::Push { path, overwrite, dry_run } => {
+            let plan = bd.plan_sync(&path).await.map_err(fail)?.push_only(overwrite);
+            execute(bd, plan, dry_run).await
+        }
+        Command::Pull { path, overwrite, dry_run } => {
+            let plan = bd.plan_sync(&path).await.map_err(fail)?.pull_only(overwrite);
+            execute(bd, plan, dry_run).await
+        }
+        Command::Sync { path, dry_run } => {
+            let plan = bd.plan_sync(&path).await.map_err(fail)?;
+            execute(bd, plan, dry_run).await
+        }
+        Command::Status { path, json } => {
+            let status = bd.status(&path).await.map_err(fail)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&status).map_err(fail)?);
+            } else {
+                print!("{}", status);
+                println!(
+                    "{} synced, {} new, {} modified, {} remote only, {} deleted, {} ignored",
+                    status.count(FileStatus::Synced),
+                    status.count(FileStatus::New),
+                    status.count(FileStatus::Modified),
+                    status.count(FileStatus::RemoteOnly),
+                    status.count(FileStatus::Deleted),
+                    status.count(FileStatus::Ignored)
+                );
+            }
+            Ok(())
+        }
+        Command::Ls { path } => {
+            for f in bd.list(&path).await.map_err(fail)? {
+                let id = f.remote_identity();
+                println!("{:>12} {} {}", id.size(), &id.hash()[..12], f.path());
+            }
+            Ok(())
+        }
+        Command::Rm { paths, keep_local, force } => {
+            let mut failed = 0;
+            for p in paths {
+                match bd.remove(&p, keep_local, force).await {
+                    Ok(f) => println!("removed {}", f.path()),
+                    Err(e) => {
+                        eprintln!("cannot remove {}: {:?}", p, e);
+                        failed += 1;
+                    }
+                }
+            }
+            bd.purge_expired().await;
+            if failed > 0 { Err(format!("{} files could not be removed", failed)) } else { Ok(()) }
+        }
+        Command::Mv { from, to } => {
+            let f = bd.rename(&from, &to).await.map_err(fail)?;
+            println!("moved {} to {}", from, f.path());
+            Ok(())
+        }
+        Command::Cp { from, to } => {
+            let f = bd.copy(&from, &to).await.map_err(fail)?;
+            println!("copied {} to {}", from, f.path());
+            Ok(())
+        }
+        Command::Versions { path } => {
+            for v in bd.versions(&path).await.map_err(fail)? {
+                let time = SystemTime::UNIX_EPOCH + Duration::from_secs(v.time);
+                println!(
+                    "{:>4} {} {:>12} {} {}",
+                    v.number,
+                    humantime::format_rfc3339_seconds(time),
+                    v.size,
+                    &v.hash[..12],
+                    v.device
+                );
             }
-            Err(e) => {
-                println!("=> Not a file: {:?}", e);
+            Ok(())
+        }
+        Command::Restore { path, version } => {
+            let f = bd.restore(&path, version).await.map_err(fail)?;
+            println!("restored {} to version {}", f.path(), version);
+            Ok(())
+        }
+        Command::Snapshot { command } => snapshot(bd, command).await,
+        Command::Trash { command } => trash(bd, command).await,
+        Command::Verify { path, rehash } => {
+            let mut bad = 0;
+            let options = VerifyOptions::builder().rehash(rehash).build();
+            for v in bd.verify(&path, Some(options)).await.map_err(fail)? {
+                match v {
+                    Verification::Ok(f) => log::info!("ok {}", f.path()),
+                    Verification::Missing(f) => {
+                        println!("missing       {}", f.path());
+                        bad += 1;
+                    }
+                    Verification::SizeMismatch(f, size) => {
+                        println!("size mismatch {} (expected {}, found {})", f.path(), f.remote_identity().size(), size);
+                        bad += 1;
+                    }
+                    Verification::Corrupt(f, hash) => {
+                        println!("corrupt       {} (expected {}, found {})", f.path(), f.remote_identity().hash(), hash);
+                        bad += 1;
+                    }
+                }
             }
+            if bad > 0 { Err(format!("{} files failed verification", bad)) } else { Ok(()) }
+        }
+        Command::Prune { dry_run } => {
+            let pruned = bd.prune(dry_run).await.map_err(fail)?;
+            print!("{}", pruned);
+            println!(
+                "{} versions and {} snapshots expired, {} {} bytes in {} blobs",
+                pruned.versions.len(),
+                pruned.snapshots.len(),
+                if dry_run { "would free" } else { "freed" },
+                pruned.bytes,
+                pruned.blobs
+            );
+            Ok(())
+        }
+        Command::Migrate { dry_run } => {
+            let report = bd.migrate(dry_run).await.map_err(fail)?;
+            print!("{}", report);
+            println!(
+                "{} moved, {} merged, {} recorded, {} missing{}",
+                report.moved.len(),
+                report.merged.len(),
+                report.recorded.len(),
+                report.missing.len(),
+                if dry_run { " (dry run)" } else { "" }
+            );
+            if report.missing.is_empty() { Ok(()) } else { Err(format!("{} files are missing", report.missing.len())) }
+        }
+        Command::Gc { dry_run } => {
+            let gc = bd.gc(dry_run).await.map_err(fail)?;
+            print!("{}", gc);
+            println!(
+                "{} orphans, {} partials, {} wrong counts, {} missing, {} legacy, {} unknown, {} journal entries{}",
+                gc.orphans.len(),
+                gc.partials.len(),
+                gc.refs.len(),
+                gc.missing.len(),
+                gc.legacy.len(),
+                gc.unknown.len(),
+                gc.journal,
+                if dry_run { " (dry run)" } else { "" }
+            );
+            if gc.missing.is_empty() { Ok(()) } else { Err(format!("{} blobs are missing", gc.missing.len())) }
         }
     }
+}
 
+/// Print `plan`, or apply it and print what happened.
+async fn execute(bd: &mut BDrive, plan: SyncPlan, dry_run: bool) -> Result<(), String> {
+    if dry_run {
+        if plan.is_noop() {
+            println!("everything up to date");
+        } else {
+            print!("{}", plan);
+        }
+        return Ok(())
+    }
 
-    Ok(())
-}
\ No newline at end of file
+    let report = bd.apply(plan).await.map_err(fail)?;
+    println!("{} files in sync, {} deleted", report.synced.len(), report.deleted.len());
+    for c in &report.conflicts {
+        println!("conflict: {}", c.path());
+    }
+    for e in &report.errors {
+        eprintln!("{:?}", e);
+    }
+    if report.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("{} operations failed", report.errors.len()))
+    }
+}
+
+async fn snapshot(bd: &mut BDrive, command: SnapshotCommand) -> Result<(), String> {
+    match command {
+        SnapshotCommand::Create { path, name } => {
+            let s = bd.snapshot(&path, name).await.map_err(fail)?;
+            println!("snapshot {} holds {} files", s.name, s.files.len());
+            Ok(())
+        }
+        SnapshotCommand::List => {
+            for s in bd.snapshots().await.map_err(fail)? {
+                let time = SystemTime::UNIX_EPOCH + Duration::from_secs(s.time);
+                println!(
+                    "{} {:>6} {} /{} {}",
+                    humantime::format_rfc3339_seconds(time),
+                    s.files.len(),
+                    s.device,
+                    s.root,
+                    s.name
+                );
+            }
+            Ok(())
+        }
+        SnapshotCommand::Restore { name, into } => {
+            let restored = match into {
+                Some(dir) => bd.restore_snapshot_into(&name, &dir).await,
+                None => bd.restore_snapshot(&name).await
+            }.map_err(fail)?;
+            for p in &restored.restored {
+                println!("restored {}", p);
+            }
+            for p in &restored.deleted {
+                println!("deleted  {}", p);
+            }
+            if let Some(backup) = &restored.backup {
+                println!("previous state kept in snapshot {}", backup);
+            }
+            for e in &restored.errors {
+                eprintln!("error: {:?}", e);
+            }
+            if restored.errors.is_empty() { Ok(()) } else { Err(format!("{} files failed", restored.errors.len())) }
+        }
+        SnapshotCommand::Delete { name } => {
+            let s = bd.delete_snapshot(&name).await.map_err(fail)?;
+            println!("deleted snapshot {}", s.name);
+            Ok(())
+        }
+    }
+}
+
+async fn trash(bd: &BDrive, command: TrashCommand) -> Result<(), String> {
+    match command {
+        TrashCommand::List { path } => {
+            for t in bd.trash(&path).await.map_err(fail)? {
+                let time = SystemTime::UNIX_EPOCH + Duration::from_secs(t.deleted);
+                println!(
+                    "{} {:>4} {:>12} {} {}",
+                    humantime::format_rfc3339_seconds(time),
+                    t.number,
+                    t.size,
+                    t.device,
+                    t.path
+                );
+            }
+            Ok(())
+        }
+        TrashCommand::Restore { path, number } => {
+            let f = bd.restore_trashed(&path, number).await.map_err(fail)?;
+            println!("restored {}", f.path());
+            Ok(())
+        }
+        TrashCommand::Purge { all } => {
+            for t in bd.purge_trash(all).await.map_err(fail)? {
+                println!("purged {} {}", t.number, t.path);
+            }
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::io::{self, Read, Write};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Serialize, Deserialize};
+
+/// Extensions of files whose content is already compressed, they're stored as they are.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "apk", "avi", "br", "bz2", "docx", "flac", "gif", "gz", "heic", "jar", "jpeg", "jpg",
+    "lz4", "m4a", "mkv", "mov", "mp3", "mp4", "odt", "ogg", "pdf", "png", "pptx", "rar", "tgz",
+    "webm", "webp", "xlsx", "xz", "zip", "zst"
+];
+
+/// How a chunk was compressed before being stored.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    Zstd,
+    Gzip
+}
+
+impl Codec {
+    /// Compress `data` at `level`, or the default level of the codec.
+    pub fn compress(&self, data: &[u8], level: Option<i32>) -> io::Result<Vec<u8>> {
+        match self {
+            Codec::Zstd => zstd::bulk::compress(data, level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL)),
+            Codec::Gzip => {
+                let level = level.map(|l| Compression::new(l.clamp(0, 9) as u32)).unwrap_or_default();
+                let mut encoder = GzEncoder::new(Vec::with_capacity(data.len() / 2), level);
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    /// Get back the `size` bytes compressed in `data`.
+    pub fn decompress(&self, data: &[u8], size: u64) -> io::Result<Vec<u8>> {
+        let decompressed = match self {
+            Codec::Zstd => zstd::bulk::decompress(data, size as usize)?,
+            Codec::Gzip => {
+                let mut decompressed = Vec::with_capacity(size as usize);
+                GzDecoder::new(data).read_to_end(&mut decompressed)?;
+                decompressed
+            }
+        };
+        if decompressed.len() as u64 != size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "decompressed size doesn't match"))
+        }
+        Ok(decompressed)
+    }
+}
+
+/// Whether the file `path` is worth compressing, judging from its extension. `skip` lists more
+/// extensions to leave alone.
+pub fn compressible(path: &str, skip: &[String]) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => {
+            let ext = ext.to_ascii_lowercase();
+            !COMPRESSED_EXTENSIONS.contains(&ext.as_str()) && !skip.iter().any(|s| s.eq_ignore_ascii_case(&ext))
+        }
+        _ => true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let data = b"bdrive ".repeat(1000);
+        for codec in [Codec::Zstd, Codec::Gzip] {
+            for level in [None, Some(1), Some(19)] {
+                let compressed = codec.compress(&data, level).unwrap();
+                assert!(compressed.len() < data.len() / 10);
+                assert_eq!(codec.decompress(&compressed, data.len() as u64).unwrap(), data);
+            }
+            let empty = codec.compress(&[], None).unwrap();
+            assert!(codec.decompress(&empty, 0).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn damaged() {
+        let data = b"bdrive ".repeat(1000);
+        for codec in [Codec::Zstd, Codec::Gzip] {
+            let compressed = codec.compress(&data, None).unwrap();
+            // the recorded size must match
+            assert!(codec.decompress(&compressed, data.len() as u64 - 1).is_err());
+            assert!(codec.decompress(&compressed[..compressed.len() / 2], data.len() as u64).is_err());
+            assert!(codec.decompress(b"not compressed at all", data.len() as u64).is_err());
+        }
+        // each codec reads its own format only
+        let gzip = Codec::Gzip.compress(&data, None).unwrap();
+        assert!(Codec::Zstd.decompress(&gzip, data.len() as u64).is_err());
+    }
+
+    #[test]
+    fn by_extension() {
+        assert!(compressible("dir/notes.txt", &[]));
+        assert!(compressible("Makefile", &[]));
+        assert!(!compressible("dir/photo.JPG", &[]));
+        assert!(!compressible("archive.tar.gz", &[]));
+        // a leading dot starts a name, not an extension
+        assert!(compressible("dir/.zip", &[]));
+        assert!(compressible("zip.d/file", &[]));
+        let skip = ["ISO".to_string()];
+        assert!(!compressible("disk.iso", &skip));
+        assert!(compressible("disk.img", &skip));
+    }
+}
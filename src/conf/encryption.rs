@@ -0,0 +1,17 @@
+use crate::conf::EncryptionConfig;
+use crate::crypto::Cipher;
+
+impl EncryptionConfig {
+    /// Build the cipher from the passphrase or the key file, at most one may be set. With
+    /// neither encryption is off.
+    pub fn to_cipher(&self) -> Result<Option<Cipher>, String> {
+        match (&self.passphrase, &self.key_file) {
+            (Some(p), None) => Ok(Some(Cipher::from_passphrase(p, &self.salt))),
+            (None, Some(f)) => std::fs::read(f)
+                .map(|k| Some(Cipher::from_key_file(&k)))
+                .map_err(|e| format!("cannot read key file {}: {}", f, e)),
+            (Some(_), Some(_)) => Err("set either encryption.passphrase or encryption.key_file, not both".to_string()),
+            (None, None) => Ok(None)
+        }
+    }
+}
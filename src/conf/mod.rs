@@ -1,26 +1,72 @@
 mod mongodb;
 mod ssh;
 mod paths;
+mod encryption;
 
 pub use paths::PathError;
 
-use serde::Deserialize;
+use serde::{Serialize, Deserialize};
+use crate::compress::Codec;
+use crate::crypto::Cipher;
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Configs {
-    pub ssh: SSHConfig,
-    pub mongodb: MongoDBConfig,
+    /// Where file contents are stored, `paths.remote` is the root directory in it.
+    #[serde(default)]
+    pub backend: Backend,
+    /// Required by the `ssh` backend.
+    pub ssh: Option<SSHConfig>,
+    /// Where the records describing the remote files are kept.
+    #[serde(default)]
+    pub index: IndexBackend,
+    /// Required by the `mongodb` index.
+    pub mongodb: Option<MongoDBConfig>,
+    /// When present file contents are encrypted before being stored.
+    pub encryption: Option<EncryptionConfig>,
+    /// When present file contents are compressed before being stored.
+    pub compression: Option<CompressionConfig>,
+    /// Files hashed and transferred at the same time.
+    #[serde(default = "default_jobs")]
+    pub jobs: usize,
+    /// Name of this machine in the version history, defaults to the host name.
+    pub device: Option<String>,
+    /// Versions and snapshots kept by `prune`, without it nothing is pruned.
+    pub retention: Option<RetentionConfig>,
+    /// How long deleted files can be restored.
+    #[serde(default)]
+    pub trash: TrashConfig,
     pub paths: PathsConf
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// A directory on a server reached through ssh.
+    #[default]
+    Ssh,
+    /// A directory on this machine, e.g. a mounted drive.
+    Local
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexBackend {
+    #[default]
+    Mongodb,
+    /// A file in the state directory of the local tree, works offline.
+    Local
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct SSHConfig {
     pub host: String,
     pub port: u16,
-    pub username: String
+    pub username: String,
+    /// Sessions kept open to the server, transfers use them side by side.
+    pub sessions: Option<usize>
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct MongoDBConfig {
     pub username: String,
     pub host: String,
@@ -28,8 +74,120 @@ pub struct MongoDBConfig {
     pub password: String
 }
 
-#[derive(Deserialize, Debug)]
+/// Encryption is on once a passphrase or a key file is set.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EncryptionConfig {
+    /// The key is derived from this, with `salt`.
+    pub passphrase: Option<String>,
+    /// File holding the key, as 64 hex digits or anything to be hashed into one.
+    pub key_file: Option<String>,
+    /// Random, written by `init`. Must be the same on every machine sharing the storage;
+    /// configurations without one use a fixed salt.
+    #[serde(default = "default_salt")]
+    pub salt: String,
+    /// Also encrypt paths and hashes in the index, so file names don't show anywhere.
+    #[serde(default)]
+    pub encrypt_paths: bool
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompressionConfig {
+    #[serde(default)]
+    pub codec: Codec,
+    /// Defaults to the usual level of the codec.
+    pub level: Option<i32>,
+    /// Extensions of files to store uncompressed, besides the well known compressed formats.
+    #[serde(default)]
+    pub skip: Vec<String>
+}
+
+/// Which versions of a file, and which snapshots of a directory, survive a prune. Each rule keeps
+/// some of them and the rest expire; the newest one is always kept.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct RetentionConfig {
+    /// Keep this many of the newest.
+    #[serde(default)]
+    pub last: usize,
+    /// Keep the newest of each day, for this many days.
+    #[serde(default)]
+    pub daily: u64,
+    /// Keep the newest of each week, for this many weeks.
+    #[serde(default)]
+    pub weekly: u64,
+    /// Keep the newest of each month, for this many months.
+    #[serde(default)]
+    pub monthly: u64
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct TrashConfig {
+    /// Days a deleted file stays in the trash, 0 deletes files right away. Expired files are
+    /// purged by the next delete, or by `trash purge`.
+    #[serde(default = "default_trash_days")]
+    pub days: u64
+}
+
+impl Default for TrashConfig {
+    fn default() -> Self {
+        Self { days: default_trash_days() }
+    }
+}
+
+fn default_trash_days() -> u64 {
+    30
+}
+
+fn default_jobs() -> usize {
+    4
+}
+
+fn default_salt() -> String {
+    "bdrive".to_string()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PathsConf {
     pub local: String,
-    pub remote: String
+    pub remote: String,
+    /// Gitignore-style patterns excluded everywhere in the local tree.
+    #[serde(default)]
+    pub ignore: Vec<String>
+}
+
+impl Configs {
+    /// A configuration syncing `local`, with placeholders for everything else.
+    pub fn template(local: String) -> Self {
+        Self {
+            backend: Backend::Ssh,
+            ssh: Some(SSHConfig {
+                host: "example.com".to_string(),
+                port: 22,
+                username: "user".to_string(),
+                sessions: None
+            }),
+            index: IndexBackend::Mongodb,
+            mongodb: Some(MongoDBConfig {
+                username: "user".to_string(),
+                host: "cluster.example.mongodb.net".to_string(),
+                port: None,
+                password: "password".to_string()
+            }),
+            encryption: Some(EncryptionConfig {
+                passphrase: None,
+                key_file: None,
+                salt: Cipher::random_salt(),
+                encrypt_paths: false
+            }),
+            compression: None,
+            jobs: default_jobs(),
+            device: None,
+            retention: None,
+            trash: TrashConfig::default(),
+            paths: PathsConf {
+                local,
+                remote: "/srv/bdrive".to_string(),
+                ignore: vec![]
+            }
+        }
+    }
 }
@@ -1,6 +1,6 @@
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 use crate::conf::PathsConf;
 
 #[derive(Debug)]
@@ -39,7 +39,14 @@ impl PathsConf {
         Ok(PathBuf::from(self.absolute(rel)?.strip_prefix(&self.local).unwrap()))
     }
 
-    pub fn to_remote(&self, rel: &str) -> PathBuf {
-        [&self.remote, rel].iter().collect::<PathBuf>()
+    /// Like `absolute`, but doesn't require the file to exist, so it cannot canonicalize: any
+    /// path that climbs up with `..` is refused.
+    pub fn to_local(&self, rel: &str) -> Result<PathBuf, PathError> {
+        let p = Path::new(rel);
+        if p.components().any(|c| matches!(c, Component::ParentDir | Component::RootDir | Component::Prefix(_))) {
+            Err(PathError::Outbound(p.to_path_buf()))
+        } else {
+            Ok([&self.local, rel].iter().collect::<PathBuf>())
+        }
     }
 }
@@ -1,14 +1,18 @@
 use crate::conf::SSHConfig;
-use crate::ssh::SSHClient;
+use crate::ssh::SSHPool;
+
+/// Sessions opened when the configuration doesn't say.
+const DEFAULT_SESSIONS: usize = 4;
 
 impl SSHConfig {
-    pub async fn connect<'a>(self) -> std::io::Result<SSHClient> {
-        let mut ssh_client = SSHClient::new();
-        ssh_client.connect(
+    /// Connect to the server, storing files under its directory `root`.
+    pub async fn connect(self, root: String) -> std::io::Result<SSHPool> {
+        SSHPool::connect(
+            self.sessions.unwrap_or(DEFAULT_SESSIONS),
+            root,
             self.username,
             self.port,
             self.host
-        ).await?;
-        Ok(ssh_client)
+        ).await
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,208 @@
+use std::num::NonZeroU32;
+use hex::{FromHex, ToHex};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{hkdf, hmac, pbkdf2};
+
+/// Rounds of PBKDF2 turning a passphrase into a key.
+const PBKDF2_ROUNDS: u32 = 100_000;
+/// Additional data of encrypted names, so they can't be passed off as chunks.
+const NAME_AAD: &[u8] = b"bdrive-name";
+/// Labels of the subkeys derived from the key, one for each use.
+const ENCRYPTION_INFO: &[u8] = b"bdrive-encryption";
+const NONCE_INFO: &[u8] = b"bdrive-nonces";
+
+/// Encrypts chunks with AES-256-GCM before they leave this machine.
+///
+/// Nonces are derived from the key and the hash of the data encrypted, so the same chunk always
+/// encrypts to the same ciphertext and is still stored only once. Distinct data never share a
+/// nonce, which is what GCM needs; the only thing revealed is which chunks are equal.
+pub struct Cipher {
+    key: LessSafeKey,
+    nonces: hmac::Key
+}
+
+impl Cipher {
+    /// A cipher using the 256 bit `key`. The encryption key and the key deriving nonces are
+    /// expanded from it with HKDF, so neither is used for two purposes.
+    pub fn new(key: [u8; 32]) -> Self {
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, &[]).extract(&key);
+        let key: UnboundKey = prk.expand(&[ENCRYPTION_INFO], &AES_256_GCM).unwrap().into();
+        Self {
+            key: LessSafeKey::new(key),
+            nonces: prk.expand(&[NONCE_INFO], hmac::HMAC_SHA256).unwrap().into()
+        }
+    }
+
+    /// A cipher with the key derived from `passphrase` and `salt`.
+    pub fn from_passphrase(passphrase: &str, salt: &str) -> Self {
+        let mut key = [0; 32];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            NonZeroU32::new(PBKDF2_ROUNDS).unwrap(),
+            salt.as_bytes(),
+            passphrase.as_bytes(),
+            &mut key
+        );
+        Self::new(key)
+    }
+
+    /// A cipher with the key read from a key file: 64 hex digits are taken as the key itself,
+    /// anything else is hashed into one.
+    pub fn from_key_file(content: &[u8]) -> Self {
+        let trimmed = String::from_utf8_lossy(content);
+        match <[u8; 32]>::from_hex(trimmed.trim()) {
+            Ok(key) => Self::new(key),
+            Err(_) => Self::new(digest(&SHA256, content).as_ref().try_into().unwrap())
+        }
+    }
+
+    /// Encrypt the chunk `data` whose plaintext hash is `hash`, returns the hex encoded nonce and
+    /// the ciphertext with its tag. `data` may be the plaintext transformed, e.g. compressed: the
+    /// nonce comes from the hash of `data` itself, so it still never repeats.
+    pub fn seal(&self, hash: &str, mut data: Vec<u8>) -> (String, Vec<u8>) {
+        let sealed: String = digest(&SHA256, &data).encode_hex();
+        let tag = hmac::sign(&self.nonces, sealed.as_bytes());
+        let nonce: [u8; NONCE_LEN] = tag.as_ref()[..NONCE_LEN].try_into().unwrap();
+        self.key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(hash.as_bytes()), &mut data)
+            .expect("chunks are far below the AES-GCM size limit");
+        (nonce.encode_hex(), data)
+    }
+
+    /// Decrypt the chunk `data` whose plaintext hash is `hash`, sealed with `nonce`.
+    pub fn open(&self, hash: &str, nonce: &str, mut data: Vec<u8>) -> Result<Vec<u8>, CryptoError> {
+        let nonce = <[u8; NONCE_LEN]>::from_hex(nonce).map_err(|_| CryptoError::Nonce(nonce.to_string()))?;
+        let len = self.key.open_in_place(Nonce::assume_unique_for_key(nonce), Aad::from(hash.as_bytes()), &mut data)
+            .map_err(|_| CryptoError::Decrypt(hash.to_string()))?
+            .len();
+        data.truncate(len);
+        Ok(data)
+    }
+
+    /// Encrypt the name `name`, returns the hex encoded nonce and ciphertext. Like chunks the
+    /// nonce is derived from the plaintext, so a name always encrypts the same way and can still
+    /// be looked up.
+    pub fn seal_name(&self, name: &str) -> String {
+        let tag = hmac::sign(&self.nonces, format!("name\0{}", name).as_bytes());
+        let nonce: [u8; NONCE_LEN] = tag.as_ref()[..NONCE_LEN].try_into().unwrap();
+        let mut data = name.as_bytes().to_vec();
+        self.key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(NAME_AAD), &mut data)
+            .expect("names are far below the AES-GCM size limit");
+        let mut sealed = nonce.to_vec();
+        sealed.extend(data);
+        sealed.encode_hex()
+    }
+
+    /// Decrypt a name encrypted by [`Cipher::seal_name`].
+    pub fn open_name(&self, sealed: &str) -> Result<String, CryptoError> {
+        let data = Vec::<u8>::from_hex(sealed).map_err(|_| CryptoError::Name(sealed.to_string()))?;
+        if data.len() < NONCE_LEN {
+            return Err(CryptoError::Name(sealed.to_string()))
+        }
+        let (nonce, data) = data.split_at(NONCE_LEN);
+        let mut data = data.to_vec();
+        let len = self.key.open_in_place(Nonce::assume_unique_for_key(nonce.try_into().unwrap()), Aad::from(NAME_AAD), &mut data)
+            .map_err(|_| CryptoError::Name(sealed.to_string()))?
+            .len();
+        data.truncate(len);
+        String::from_utf8(data).map_err(|_| CryptoError::Name(sealed.to_string()))
+    }
+
+    /// A new random salt for [`Cipher::from_passphrase`], hex encoded.
+    pub fn random_salt() -> String {
+        let mut salt = [0; 16];
+        SystemRandom::new().fill(&mut salt).expect("the system has a random generator");
+        salt.encode_hex()
+    }
+
+    /// Size of the ciphertext of `size` bytes of plaintext.
+    pub fn sealed_size(size: u64) -> u64 {
+        size + AES_256_GCM.tag_len() as u64
+    }
+}
+
+impl std::fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Cipher {{ algorithm: AES_256_GCM }}")
+    }
+}
+
+#[derive(Debug)]
+pub enum CryptoError {
+    /// The recorded nonce, here included, is malformed.
+    Nonce(String),
+    /// The chunk with this hash doesn't decrypt: wrong key or tampered content.
+    Decrypt(String),
+    /// The chunk with this hash is encrypted but no key is configured.
+    NoKey(String),
+    /// The encrypted name, here included, doesn't decrypt.
+    Name(String)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cipher() -> Cipher {
+        Cipher::new([7; 32])
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let c = cipher();
+        let (nonce, sealed) = c.seal("h", b"some chunk".to_vec());
+        assert_eq!(sealed.len() as u64, Cipher::sealed_size(10));
+        assert_eq!(c.open("h", &nonce, sealed.clone()).unwrap(), b"some chunk");
+        // the same data seals the same way, other data gets another nonce
+        assert_eq!(c.seal("h", b"some chunk".to_vec()), (nonce.clone(), sealed.clone()));
+        assert_ne!(c.seal("h", b"other chunk".to_vec()).0, nonce);
+
+        let mut tampered = sealed.clone();
+        tampered[0] ^= 1;
+        assert!(matches!(c.open("h", &nonce, tampered), Err(CryptoError::Decrypt(h)) if h == "h"));
+        // bound to the hash it was sealed with
+        assert!(matches!(c.open("g", &nonce, sealed.clone()), Err(CryptoError::Decrypt(_))));
+        assert!(matches!(Cipher::new([8; 32]).open("h", &nonce, sealed.clone()), Err(CryptoError::Decrypt(_))));
+        assert!(matches!(c.open("h", "zz", sealed), Err(CryptoError::Nonce(n)) if n == "zz"));
+    }
+
+    #[test]
+    fn names() {
+        let c = cipher();
+        let sealed = c.seal_name("dir/a.txt");
+        assert_eq!(sealed, c.seal_name("dir/a.txt"));
+        assert!(!sealed.contains("a.txt"));
+        assert_eq!(c.open_name(&sealed).unwrap(), "dir/a.txt");
+
+        let mut tampered = sealed.clone().into_bytes();
+        let last = tampered.len() - 1;
+        tampered[last] = if tampered[last] == b'0' { b'1' } else { b'0' };
+        assert!(matches!(c.open_name(std::str::from_utf8(&tampered).unwrap()), Err(CryptoError::Name(_))));
+        assert!(matches!(c.open_name("00"), Err(CryptoError::Name(_))));
+        assert!(matches!(c.open_name("not hex"), Err(CryptoError::Name(_))));
+        // a name doesn't open as a chunk, nor with another key
+        assert!(matches!(Cipher::new([8; 32]).open_name(&sealed), Err(CryptoError::Name(_))));
+        let (nonce, data) = sealed.split_at(NONCE_LEN * 2);
+        assert!(c.open("", nonce, Vec::from_hex(data).unwrap()).is_err());
+    }
+
+    #[test]
+    fn keys() {
+        let hex = "07".repeat(32);
+        let (nonce, sealed) = cipher().seal("h", b"data".to_vec());
+        // 64 hex digits are the key itself, surrounding whitespace aside
+        let from_hex = Cipher::from_key_file(format!("{}\n", hex).as_bytes());
+        assert_eq!(from_hex.open("h", &nonce, sealed.clone()).unwrap(), b"data");
+        // anything else is hashed
+        let hashed = Cipher::from_key_file(b"a secret");
+        assert!(hashed.open("h", &nonce, sealed).is_err());
+        let (nonce, sealed) = hashed.seal("h", b"data".to_vec());
+        assert_eq!(Cipher::new(digest(&SHA256, b"a secret").as_ref().try_into().unwrap()).open("h", &nonce, sealed).unwrap(), b"data");
+
+        let (nonce, sealed) = Cipher::from_passphrase("pass", "salt").seal("h", b"data".to_vec());
+        assert_eq!(Cipher::from_passphrase("pass", "salt").open("h", &nonce, sealed.clone()).unwrap(), b"data");
+        assert!(Cipher::from_passphrase("pass", "other").open("h", &nonce, sealed).is_err());
+        assert_ne!(Cipher::random_salt(), Cipher::random_salt());
+    }
+}
@@ -0,0 +1,381 @@
+use std::sync::Arc;
+use async_trait::async_trait;
+use super::{Blob, Chunk, DatabaseError, Index, RemoteFile, Snapshot, Tombstone, Trashed, Version};
+use crate::crypto::Cipher;
+
+/// Index keeping paths and content hashes of the records encrypted, on top of another index.
+/// Versions, deleted files, tombstones and snapshots are encrypted the same way, along with the
+/// names of their devices.
+///
+/// Names encrypt deterministically, so a record is still found by its path, but the encrypted
+/// path is a single opaque string: neither file names nor the directory structure show. Listing
+/// a directory therefore reads every record and filters here. Blob records only hold the names
+/// of blobs, which are already hashes of the ciphertext, and pass through unchanged.
+#[derive(Debug)]
+pub struct EncryptedIndex {
+    inner: Box<dyn Index>,
+    cipher: Arc<Cipher>
+}
+
+impl EncryptedIndex {
+    pub fn new(inner: Box<dyn Index>, cipher: Arc<Cipher>) -> Self {
+        Self { inner, cipher }
+    }
+
+    fn seal(&self, file: RemoteFile) -> RemoteFile {
+        let chunks = file.chunks.map(|c| c.into_iter().map(|c| self.seal_chunk(c)).collect());
+        RemoteFile {
+            path: self.cipher.seal_name(&file.path),
+            hash: self.cipher.seal_name(&file.hash),
+            size: file.size,
+            chunks
+        }
+    }
+
+    fn open(&self, file: RemoteFile) -> Result<RemoteFile, DatabaseError> {
+        let chunks = match file.chunks {
+            Some(c) => Some(c.into_iter().map(|c| self.open_chunk(c)).collect::<Result<_, _>>()?),
+            None => None
+        };
+        Ok(RemoteFile {
+            path: self.cipher.open_name(&file.path)?,
+            hash: self.cipher.open_name(&file.hash)?,
+            size: file.size,
+            chunks
+        })
+    }
+
+    fn seal_version(&self, version: Version) -> Version {
+        Version {
+            path: self.cipher.seal_name(&version.path),
+            hash: self.cipher.seal_name(&version.hash),
+            chunks: version.chunks.into_iter().map(|c| self.seal_chunk(c)).collect(),
+            device: self.cipher.seal_name(&version.device),
+            ..version
+        }
+    }
+
+    fn open_version(&self, version: Version) -> Result<Version, DatabaseError> {
+        Ok(Version {
+            path: self.cipher.open_name(&version.path)?,
+            hash: self.cipher.open_name(&version.hash)?,
+            chunks: version.chunks.into_iter().map(|c| self.open_chunk(c)).collect::<Result<_, _>>()?,
+            device: self.cipher.open_name(&version.device)?,
+            ..version
+        })
+    }
+
+    fn seal_trashed(&self, trashed: Trashed) -> Trashed {
+        Trashed {
+            path: self.cipher.seal_name(&trashed.path),
+            hash: self.cipher.seal_name(&trashed.hash),
+            chunks: trashed.chunks.into_iter().map(|c| self.seal_chunk(c)).collect(),
+            device: self.cipher.seal_name(&trashed.device),
+            ..trashed
+        }
+    }
+
+    fn open_trashed(&self, trashed: Trashed) -> Result<Trashed, DatabaseError> {
+        Ok(Trashed {
+            path: self.cipher.open_name(&trashed.path)?,
+            hash: self.cipher.open_name(&trashed.hash)?,
+            chunks: trashed.chunks.into_iter().map(|c| self.open_chunk(c)).collect::<Result<_, _>>()?,
+            device: self.cipher.open_name(&trashed.device)?,
+            ..trashed
+        })
+    }
+
+    fn seal_tombstone(&self, tombstone: Tombstone) -> Tombstone {
+        Tombstone {
+            path: self.cipher.seal_name(&tombstone.path),
+            hash: self.cipher.seal_name(&tombstone.hash),
+            device: self.cipher.seal_name(&tombstone.device),
+            ..tombstone
+        }
+    }
+
+    fn open_tombstone(&self, tombstone: Tombstone) -> Result<Tombstone, DatabaseError> {
+        Ok(Tombstone {
+            path: self.cipher.open_name(&tombstone.path)?,
+            hash: self.cipher.open_name(&tombstone.hash)?,
+            device: self.cipher.open_name(&tombstone.device)?,
+            ..tombstone
+        })
+    }
+
+    fn seal_snapshot(&self, snapshot: Snapshot) -> Snapshot {
+        Snapshot {
+            name: self.cipher.seal_name(&snapshot.name),
+            root: self.cipher.seal_name(&snapshot.root),
+            device: self.cipher.seal_name(&snapshot.device),
+            files: snapshot.files.into_iter().map(|f| self.seal(f)).collect(),
+            ..snapshot
+        }
+    }
+
+    fn open_snapshot(&self, snapshot: Snapshot) -> Result<Snapshot, DatabaseError> {
+        Ok(Snapshot {
+            name: self.cipher.open_name(&snapshot.name)?,
+            root: self.cipher.open_name(&snapshot.root)?,
+            device: self.cipher.open_name(&snapshot.device)?,
+            files: snapshot.files.into_iter().map(|f| self.open(f)).collect::<Result<_, _>>()?,
+            ..snapshot
+        })
+    }
+
+    /// The blob of a plain chunk is named after its hash, which has to be kept readable there.
+    fn seal_chunk(&self, mut chunk: Chunk) -> Chunk {
+        if chunk.blob.is_none() {
+            chunk.blob = Some(chunk.hash.clone());
+        }
+        chunk.hash = self.cipher.seal_name(&chunk.hash);
+        chunk
+    }
+
+    fn open_chunk(&self, mut chunk: Chunk) -> Result<Chunk, DatabaseError> {
+        chunk.hash = self.cipher.open_name(&chunk.hash)?;
+        if chunk.blob.as_deref() == Some(chunk.hash.as_str()) {
+            chunk.blob = None;
+        }
+        Ok(chunk)
+    }
+}
+
+#[async_trait]
+impl Index for EncryptedIndex {
+    async fn get(&self, path: &str) -> Result<Option<RemoteFile>, DatabaseError> {
+        match self.inner.get(&self.cipher.seal_name(path)).await? {
+            Some(f) => Ok(Some(self.open(f)?)),
+            None => Ok(None)
+        }
+    }
+
+    async fn list(&self, dir: &str) -> Result<Vec<RemoteFile>, DatabaseError> {
+        let mut files = vec![];
+        for f in self.inner.list("").await? {
+            let f = self.open(f)?;
+            if dir.is_empty() || f.path == dir || f.path.strip_prefix(dir).is_some_and(|r| r.starts_with('/')) {
+                files.push(f);
+            }
+        }
+        Ok(files)
+    }
+
+    async fn insert(&self, file: RemoteFile) -> Result<(), DatabaseError> {
+        let path = file.path.clone();
+        match self.inner.insert(self.seal(file)).await {
+            // report the path the caller knows
+            Err(DatabaseError::Duplicate(_)) => Err(DatabaseError::Duplicate(path)),
+            r => r
+        }
+    }
+
+    async fn update(&self, file: RemoteFile) -> Result<bool, DatabaseError> {
+        self.inner.update(self.seal(file)).await
+    }
+
+    async fn delete(&self, path: &str) -> Result<bool, DatabaseError> {
+        self.inner.delete(&self.cipher.seal_name(path)).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<bool, DatabaseError> {
+        match self.inner.rename(&self.cipher.seal_name(from), &self.cipher.seal_name(to)).await {
+            Err(DatabaseError::Duplicate(_)) => Err(DatabaseError::Duplicate(to.to_string())),
+            r => r
+        }
+    }
+
+    async fn blob_ref(&self, hash: &str, size: u64) -> Result<u64, DatabaseError> {
+        self.inner.blob_ref(hash, size).await
+    }
+
+    async fn blob_unref(&self, hash: &str) -> Result<u64, DatabaseError> {
+        self.inner.blob_unref(hash).await
+    }
+
+    async fn blobs(&self) -> Result<Vec<Blob>, DatabaseError> {
+        self.inner.blobs().await
+    }
+
+    async fn set_blob(&self, blob: Blob) -> Result<(), DatabaseError> {
+        self.inner.set_blob(blob).await
+    }
+
+    async fn insert_version(&self, version: Version) -> Result<(), DatabaseError> {
+        let path = version.path.clone();
+        match self.inner.insert_version(self.seal_version(version)).await {
+            Err(DatabaseError::Duplicate(_)) => Err(DatabaseError::Duplicate(path)),
+            r => r
+        }
+    }
+
+    async fn versions(&self, dir: &str) -> Result<Vec<Version>, DatabaseError> {
+        let mut versions = vec![];
+        for v in self.inner.versions("").await? {
+            let v = self.open_version(v)?;
+            if dir.is_empty() || v.path == dir || v.path.strip_prefix(dir).is_some_and(|r| r.starts_with('/')) {
+                versions.push(v);
+            }
+        }
+        // the order of the encrypted paths means nothing
+        versions.sort_by(|a, b| (&a.path, a.number).cmp(&(&b.path, b.number)));
+        Ok(versions)
+    }
+
+    async fn delete_version(&self, path: &str, number: u64) -> Result<bool, DatabaseError> {
+        self.inner.delete_version(&self.cipher.seal_name(path), number).await
+    }
+
+    async fn rename_versions(&self, from: &str, to: &str) -> Result<u64, DatabaseError> {
+        self.inner.rename_versions(&self.cipher.seal_name(from), &self.cipher.seal_name(to)).await
+    }
+
+    async fn insert_trash(&self, trashed: Trashed) -> Result<(), DatabaseError> {
+        let path = trashed.path.clone();
+        match self.inner.insert_trash(self.seal_trashed(trashed)).await {
+            Err(DatabaseError::Duplicate(_)) => Err(DatabaseError::Duplicate(path)),
+            r => r
+        }
+    }
+
+    async fn trash(&self, dir: &str) -> Result<Vec<Trashed>, DatabaseError> {
+        let mut trash = vec![];
+        for t in self.inner.trash("").await? {
+            let t = self.open_trashed(t)?;
+            if dir.is_empty() || t.path == dir || t.path.strip_prefix(dir).is_some_and(|r| r.starts_with('/')) {
+                trash.push(t);
+            }
+        }
+        trash.sort_by(|a, b| (&a.path, a.number).cmp(&(&b.path, b.number)));
+        Ok(trash)
+    }
+
+    async fn delete_trash(&self, path: &str, number: u64) -> Result<bool, DatabaseError> {
+        self.inner.delete_trash(&self.cipher.seal_name(path), number).await
+    }
+
+    async fn set_tombstone(&self, tombstone: Tombstone) -> Result<(), DatabaseError> {
+        self.inner.set_tombstone(self.seal_tombstone(tombstone)).await
+    }
+
+    async fn tombstones(&self, dir: &str) -> Result<Vec<Tombstone>, DatabaseError> {
+        let mut tombstones = vec![];
+        for t in self.inner.tombstones("").await? {
+            let t = self.open_tombstone(t)?;
+            if dir.is_empty() || t.path == dir || t.path.strip_prefix(dir).is_some_and(|r| r.starts_with('/')) {
+                tombstones.push(t);
+            }
+        }
+        Ok(tombstones)
+    }
+
+    async fn insert_snapshot(&self, snapshot: Snapshot) -> Result<(), DatabaseError> {
+        let name = snapshot.name.clone();
+        match self.inner.insert_snapshot(self.seal_snapshot(snapshot)).await {
+            Err(DatabaseError::Duplicate(_)) => Err(DatabaseError::Duplicate(name)),
+            r => r
+        }
+    }
+
+    async fn get_snapshot(&self, name: &str) -> Result<Option<Snapshot>, DatabaseError> {
+        match self.inner.get_snapshot(&self.cipher.seal_name(name)).await? {
+            Some(s) => Ok(Some(self.open_snapshot(s)?)),
+            None => Ok(None)
+        }
+    }
+
+    async fn snapshots(&self) -> Result<Vec<Snapshot>, DatabaseError> {
+        self.inner.snapshots().await?.into_iter().map(|s| self.open_snapshot(s)).collect()
+    }
+
+    async fn delete_snapshot(&self, name: &str) -> Result<bool, DatabaseError> {
+        self.inner.delete_snapshot(&self.cipher.seal_name(name)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use super::*;
+    use crate::db::LocalIndex;
+    use crate::testutil::TempDir;
+
+    fn open_index(dir: &TempDir, key: u8) -> EncryptedIndex {
+        let inner = LocalIndex::open(dir.join("index.json")).unwrap();
+        EncryptedIndex::new(Box::new(inner), Arc::new(Cipher::new([key; 32])))
+    }
+
+    fn file(path: &str, hash: &str) -> RemoteFile {
+        RemoteFile::new(path.to_string(), hash.to_string(), 4).with_chunks(vec![Chunk::plain(hash.to_string(), 4)])
+    }
+
+    fn version(path: &str, number: u64) -> Version {
+        Version {
+            path: path.to_string(),
+            number,
+            hash: format!("{}-{}", path, number),
+            size: 4,
+            chunks: vec![Chunk::plain("c".to_string(), 4)],
+            time: number,
+            device: "laptop".to_string()
+        }
+    }
+
+    #[test]
+    fn files_sealed() {
+        let dir = TempDir::new();
+        let index = open_index(&dir, 1);
+        block_on(async {
+            index.insert(file("dir/a.txt", "h1")).await.unwrap();
+            assert!(matches!(index.insert(file("dir/a.txt", "h2")).await, Err(DatabaseError::Duplicate(p)) if p == "dir/a.txt"));
+
+            let a = index.get("dir/a.txt").await.unwrap().unwrap();
+            assert_eq!((a.path.as_str(), a.hash.as_str()), ("dir/a.txt", "h1"));
+            // a plain chunk is still stored under its hash
+            assert_eq!(a.chunks(), vec![Chunk::plain("h1".to_string(), 4)]);
+        });
+
+        // only the blob names show in the inner index
+        let raw = std::fs::read_to_string(dir.join("index.json")).unwrap();
+        assert!(!raw.contains("a.txt") && !raw.contains("dir/"));
+        let inner = block_on(LocalIndex::open(dir.join("index.json")).unwrap().list("")).unwrap();
+        assert_eq!(inner[0].chunks.as_ref().unwrap()[0].blob(), "h1");
+
+        // another key reads nothing
+        assert!(matches!(block_on(open_index(&dir, 2).list("")), Err(DatabaseError::Crypto(_))));
+        assert!(block_on(open_index(&dir, 2).get("dir/a.txt")).unwrap().is_none());
+    }
+
+    #[test]
+    fn listing_filters() {
+        let dir = TempDir::new();
+        let index = open_index(&dir, 1);
+        block_on(async {
+            for path in ["dir", "dir/a", "dir/sub/b", "dir2/c", "dirx"] {
+                index.insert(file(path, "h")).await.unwrap();
+            }
+            let mut paths: Vec<String> = index.list("dir").await.unwrap().into_iter().map(|f| f.path).collect();
+            paths.sort();
+            assert_eq!(paths, ["dir", "dir/a", "dir/sub/b"]);
+            assert_eq!(index.list("").await.unwrap().len(), 5);
+            assert!(index.list("di").await.unwrap().is_empty());
+
+            for (path, number) in [("dir/b", 2), ("dir2/a", 1), ("dir/b", 1), ("dir/a", 1)] {
+                index.insert_version(version(path, number)).await.unwrap();
+            }
+            // in order of path and number, whatever the order of the encrypted paths
+            let versions: Vec<(String, u64, String)> = index.versions("dir").await.unwrap().into_iter()
+                .map(|v| (v.path, v.number, v.device))
+                .collect();
+            assert_eq!(versions, [
+                ("dir/a".to_string(), 1, "laptop".to_string()),
+                ("dir/b".to_string(), 1, "laptop".to_string()),
+                ("dir/b".to_string(), 2, "laptop".to_string())
+            ]);
+
+            index.set_tombstone(Tombstone { path: "dir/a".to_string(), hash: "h".to_string(), size: 4, deleted: 0, device: "laptop".to_string() }).await.unwrap();
+            assert_eq!(index.tombstones("dir").await.unwrap()[0].path, "dir/a");
+            assert!(index.tombstones("dir2").await.unwrap().is_empty());
+        });
+    }
+}
@@ -1,22 +1,159 @@
 use serde::{Serialize, Deserialize};
+use crate::compress::Codec;
 use crate::fs::File;
-use crate::fs::state::Remote;
+use crate::fs::state::{Identity, Remote};
 
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize)]
 pub struct Local;
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize)]
 pub struct Sync;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RemoteFile {
     pub path: String,
     pub hash: String,
     pub size: u64,
+    /// Pieces of the content, in order. Records written before chunking was introduced have
+    /// none, their content is a single blob named after the whole file hash.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunks: Option<Vec<Chunk>>
+}
+
+/// A piece of a file content, stored as the blob named after its hash.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Chunk {
+    /// Hash and size of the plaintext.
+    pub hash: String,
+    pub size: u64,
+    /// Name of the blob when it isn't `hash`, i.e. the hash of the stored content.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blob: Option<String>,
+    /// Hex encoded nonce, for encrypted chunks.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    /// How the chunk was compressed, if it was.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub codec: Option<Codec>,
+    /// Size of the stored content when it isn't `size`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stored: Option<u64>
+}
+
+impl Chunk {
+    /// A chunk stored as it is.
+    pub fn plain(hash: String, size: u64) -> Self {
+        Self { hash, size, blob: None, nonce: None, codec: None, stored: None }
+    }
+
+    /// Name of the blob holding this chunk.
+    pub fn blob(&self) -> &str {
+        self.blob.as_deref().unwrap_or(&self.hash)
+    }
+
+    /// Size of the blob holding this chunk.
+    pub fn stored_size(&self) -> u64 {
+        self.stored.unwrap_or(self.size)
+    }
+
+    /// Whether the blob holds exactly the plaintext.
+    pub fn is_plain(&self) -> bool {
+        self.nonce.is_none() && self.codec.is_none()
+    }
+}
+
+/// A content a file had at some point. Like the file record it holds a reference to each of its
+/// chunks, so the content stays stored until the version is removed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Version {
+    pub path: String,
+    /// Counts the versions of `path`, from 1.
+    pub number: u64,
+    pub hash: String,
+    pub size: u64,
+    pub chunks: Vec<Chunk>,
+    /// When it was uploaded, in seconds since the Unix epoch.
+    pub time: u64,
+    /// Name of the machine that uploaded it.
+    pub device: String
+}
+
+/// A remote file that was deleted. It keeps holding a reference to each of its chunks, so it can
+/// be restored until the trash is purged.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Trashed {
+    pub path: String,
+    /// Counts the deletions of `path`, from 1.
+    pub number: u64,
+    pub hash: String,
+    pub size: u64,
+    pub chunks: Vec<Chunk>,
+    /// When it was deleted, in seconds since the Unix epoch.
+    pub deleted: u64,
+    /// Name of the machine that deleted it.
+    pub device: String
+}
+
+/// Marks a path whose file was deleted, so machines that still have it know it's gone instead of
+/// never uploaded. Only the last deletion of a path is kept; it holds no content.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Tombstone {
+    pub path: String,
+    /// Content the file had when it was deleted.
+    pub hash: String,
+    pub size: u64,
+    /// When it was deleted, in seconds since the Unix epoch.
+    pub deleted: u64,
+    /// Name of the machine that deleted it.
+    pub device: String
+}
+
+/// The files inside `root` as they were at some moment. Like a version it holds a reference to
+/// each chunk of its files, which stay stored until the snapshot is deleted.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    pub name: String,
+    /// Directory captured, empty for the whole tree.
+    pub root: String,
+    /// When it was taken, in seconds since the Unix epoch.
+    pub time: u64,
+    /// Name of the machine that took it.
+    pub device: String,
+    pub files: Vec<RemoteFile>
+}
+
+/// A content stored once in the storage, `refs` counts the records using it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Blob {
+    pub hash: String,
+    pub size: u64,
+    pub refs: i64
+}
+
+impl Tombstone {
+    /// Identity of the content the file had when it was deleted.
+    pub fn identity(&self) -> Identity {
+        Identity::new(self.hash.clone(), self.size)
+    }
 }
 
 impl RemoteFile {
     pub fn new(path: String, hash: String, size: u64) -> Self {
-        Self { path, hash, size }
+        Self { path, hash, size, chunks: None }
+    }
+
+    pub fn with_chunks(mut self, chunks: Vec<Chunk>) -> Self {
+        self.chunks = Some(chunks);
+        self
+    }
+
+    /// Blobs making up the content of this file.
+    pub fn chunks(&self) -> Vec<Chunk> {
+        match &self.chunks {
+            Some(c) => c.clone(),
+            None => vec![Chunk::plain(self.hash.clone(), self.size)]
+        }
     }
 
     // pub fn push(self, c: &mut Collection<Self>) {
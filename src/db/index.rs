@@ -0,0 +1,82 @@
+use std::fmt::Debug;
+use async_trait::async_trait;
+use super::{Blob, DatabaseError, RemoteFile, Snapshot, Tombstone, Trashed, Version};
+
+/// Where the records describing the remote files are kept, each record is identified by its
+/// path relative to the local root.
+#[async_trait]
+pub trait Index: Debug + Send + Sync {
+    async fn get(&self, path: &str) -> Result<Option<RemoteFile>, DatabaseError>;
+
+    /// Every record inside directory `dir` (or `dir` itself, if it's a file), an empty `dir`
+    /// lists everything.
+    async fn list(&self, dir: &str) -> Result<Vec<RemoteFile>, DatabaseError>;
+
+    /// Add a new record, fails if its path is already taken.
+    async fn insert(&self, file: RemoteFile) -> Result<(), DatabaseError>;
+
+    /// Replace the record with the same path, returns whether there was one.
+    async fn update(&self, file: RemoteFile) -> Result<bool, DatabaseError>;
+
+    /// Remove the record of `path`, returns whether there was one.
+    async fn delete(&self, path: &str) -> Result<bool, DatabaseError>;
+
+    /// Move the record of `from` to `to`, returns whether there was one.
+    async fn rename(&self, from: &str, to: &str) -> Result<bool, DatabaseError>;
+
+    /// Count a new reference to the blob `hash`, creating its record if needed. Returns the
+    /// updated count.
+    async fn blob_ref(&self, hash: &str, size: u64) -> Result<u64, DatabaseError>;
+
+    /// Drop a reference to the blob `hash`, its record is removed when none is left. Returns the
+    /// updated count.
+    async fn blob_unref(&self, hash: &str) -> Result<u64, DatabaseError>;
+
+    /// Every blob record.
+    async fn blobs(&self) -> Result<Vec<Blob>, DatabaseError>;
+
+    /// Replace the record of `blob.hash`, creating it if needed. A record without references is
+    /// removed.
+    async fn set_blob(&self, blob: Blob) -> Result<(), DatabaseError>;
+
+    /// Add a version record, fails if its path already has one with the same number.
+    async fn insert_version(&self, version: Version) -> Result<(), DatabaseError>;
+
+    /// Every version of the files inside directory `dir`, like `list`, ordered by path and
+    /// number.
+    async fn versions(&self, dir: &str) -> Result<Vec<Version>, DatabaseError>;
+
+    /// Remove version `number` of `path`, returns whether there was one.
+    async fn delete_version(&self, path: &str, number: u64) -> Result<bool, DatabaseError>;
+
+    /// Move the versions of `from` to `to`, numbered in order after the versions `to` already
+    /// has, returns how many were moved.
+    async fn rename_versions(&self, from: &str, to: &str) -> Result<u64, DatabaseError>;
+
+    /// Add a deleted file to the trash, fails if its path already has an entry with the same
+    /// number.
+    async fn insert_trash(&self, trashed: Trashed) -> Result<(), DatabaseError>;
+
+    /// Every deleted file inside directory `dir`, like `list`, ordered by path and number.
+    async fn trash(&self, dir: &str) -> Result<Vec<Trashed>, DatabaseError>;
+
+    /// Remove entry `number` of `path` from the trash, returns whether there was one.
+    async fn delete_trash(&self, path: &str, number: u64) -> Result<bool, DatabaseError>;
+
+    /// Record the deletion of a path, replacing any previous tombstone of it.
+    async fn set_tombstone(&self, tombstone: Tombstone) -> Result<(), DatabaseError>;
+
+    /// Tombstones of the paths inside directory `dir`, like `list`.
+    async fn tombstones(&self, dir: &str) -> Result<Vec<Tombstone>, DatabaseError>;
+
+    /// Add a snapshot, fails if its name is taken.
+    async fn insert_snapshot(&self, snapshot: Snapshot) -> Result<(), DatabaseError>;
+
+    async fn get_snapshot(&self, name: &str) -> Result<Option<Snapshot>, DatabaseError>;
+
+    /// Every snapshot, oldest first.
+    async fn snapshots(&self) -> Result<Vec<Snapshot>, DatabaseError>;
+
+    /// Remove the snapshot `name`, returns whether there was one.
+    async fn delete_snapshot(&self, name: &str) -> Result<bool, DatabaseError>;
+}
@@ -0,0 +1,430 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use async_trait::async_trait;
+use serde::{Serialize, Deserialize};
+use super::{Blob, DatabaseError, Index, RemoteFile, Snapshot, Tombstone, Trashed, Version};
+
+/// Index kept in a JSON file, for working without a database server. The whole file is
+/// rewritten on every change, so it's meant for trees of a reasonable size. Changes are made
+/// under a lock file and on the tables read again from disk, so several processes can share it.
+#[derive(Debug)]
+pub struct LocalIndex {
+    path: PathBuf,
+    tables: Mutex<Tables>
+}
+
+/// On disk layout of the index.
+#[derive(Serialize, Deserialize, Default)]
+struct Data {
+    #[serde(default)]
+    files: Vec<RemoteFile>,
+    #[serde(default)]
+    blobs: Vec<Blob>,
+    #[serde(default)]
+    versions: Vec<Version>,
+    #[serde(default)]
+    snapshots: Vec<Snapshot>,
+    #[serde(default)]
+    trash: Vec<Trashed>,
+    #[serde(default)]
+    tombstones: Vec<Tombstone>
+}
+
+#[derive(Debug)]
+struct Tables {
+    files: BTreeMap<String, RemoteFile>,
+    blobs: BTreeMap<String, Blob>,
+    versions: BTreeMap<(String, u64), Version>,
+    snapshots: BTreeMap<String, Snapshot>,
+    trash: BTreeMap<(String, u64), Trashed>,
+    tombstones: BTreeMap<String, Tombstone>
+}
+
+impl From<Data> for Tables {
+    fn from(value: Data) -> Self {
+        Self {
+            files: value.files.into_iter().map(|f| (f.path.clone(), f)).collect(),
+            blobs: value.blobs.into_iter().map(|b| (b.hash.clone(), b)).collect(),
+            versions: value.versions.into_iter().map(|v| ((v.path.clone(), v.number), v)).collect(),
+            snapshots: value.snapshots.into_iter().map(|s| (s.name.clone(), s)).collect(),
+            trash: value.trash.into_iter().map(|t| ((t.path.clone(), t.number), t)).collect(),
+            tombstones: value.tombstones.into_iter().map(|t| (t.path.clone(), t)).collect()
+        }
+    }
+}
+
+impl From<&Tables> for Data {
+    fn from(value: &Tables) -> Self {
+        Self {
+            files: value.files.values().cloned().collect(),
+            blobs: value.blobs.values().cloned().collect(),
+            versions: value.versions.values().cloned().collect(),
+            snapshots: value.snapshots.values().cloned().collect(),
+            trash: value.trash.values().cloned().collect(),
+            tombstones: value.tombstones.values().cloned().collect()
+        }
+    }
+}
+
+impl LocalIndex {
+    /// Open the index stored at `path`, a missing file is an empty index.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, DatabaseError> {
+        let path = path.into();
+        let tables = Mutex::new(Self::read(&path)?);
+        Ok(Self { path, tables })
+    }
+
+    fn read(path: &Path) -> Result<Tables, DatabaseError> {
+        let data: Data = match std::fs::read_to_string(path) {
+            Ok(s) => serde_json::from_str(&s).map_err(|e| DatabaseError::IO(e.into()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Data::default(),
+            Err(e) => return Err(e.into())
+        };
+        Ok(data.into())
+    }
+
+    /// Apply `change` to the index and write it. Another process may have changed the file
+    /// since it was read, so it's read again under the lock file first; the tables in memory
+    /// are replaced only once the new ones are written, a failed change leaves them as they were.
+    fn write<T>(&self, change: impl FnOnce(&mut Tables) -> Result<T, DatabaseError>) -> Result<T, DatabaseError> {
+        let mut tables = self.tables.lock().unwrap();
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let lock = File::create(self.path.with_extension("lock"))?;
+        lock.lock()?;
+        let mut changed = Self::read(&self.path)?;
+        let result = change(&mut changed)?;
+        self.save(&changed)?;
+        *tables = changed;
+        Ok(result)
+    }
+
+    /// Write the index to a temporary file and move it in place, so a crash never leaves it
+    /// half written.
+    fn save(&self, tables: &Tables) -> Result<(), DatabaseError> {
+        let tmp = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_string(&Data::from(tables)).map_err(|e| DatabaseError::IO(e.into()))?)?;
+        std::fs::rename(tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Index for LocalIndex {
+    async fn get(&self, path: &str) -> Result<Option<RemoteFile>, DatabaseError> {
+        Ok(self.tables.lock().unwrap().files.get(path).cloned())
+    }
+
+    async fn list(&self, dir: &str) -> Result<Vec<RemoteFile>, DatabaseError> {
+        let prefix = format!("{}/", dir);
+        Ok(self.tables.lock().unwrap().files.values()
+            .filter(|f| dir.is_empty() || f.path == dir || f.path.starts_with(&prefix))
+            .cloned()
+            .collect())
+    }
+
+    async fn insert(&self, file: RemoteFile) -> Result<(), DatabaseError> {
+        self.write(|tables| {
+            if tables.files.contains_key(&file.path) {
+                return Err(DatabaseError::Duplicate(file.path))
+            }
+            tables.files.insert(file.path.clone(), file);
+            Ok(())
+        })
+    }
+
+    async fn update(&self, file: RemoteFile) -> Result<bool, DatabaseError> {
+        self.write(|tables| Ok(match tables.files.get_mut(&file.path) {
+            Some(f) => {
+                *f = file;
+                true
+            }
+            None => false
+        }))
+    }
+
+    async fn delete(&self, path: &str) -> Result<bool, DatabaseError> {
+        self.write(|tables| Ok(tables.files.remove(path).is_some()))
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<bool, DatabaseError> {
+        self.write(|tables| {
+            if tables.files.contains_key(to) {
+                return Err(DatabaseError::Duplicate(to.to_string()))
+            }
+            Ok(match tables.files.remove(from) {
+                Some(mut f) => {
+                    f.path = to.to_string();
+                    tables.files.insert(f.path.clone(), f);
+                    true
+                }
+                None => false
+            })
+        })
+    }
+
+    async fn blob_ref(&self, hash: &str, size: u64) -> Result<u64, DatabaseError> {
+        self.write(|tables| {
+            let blob = tables.blobs.entry(hash.to_string())
+                .or_insert_with(|| Blob { hash: hash.to_string(), size, refs: 0 });
+            blob.refs += 1;
+            Ok(blob.refs as u64)
+        })
+    }
+
+    async fn blob_unref(&self, hash: &str) -> Result<u64, DatabaseError> {
+        self.write(|tables| {
+            let refs = match tables.blobs.get_mut(hash) {
+                Some(b) => {
+                    b.refs -= 1;
+                    b.refs.max(0) as u64
+                }
+                None => return Ok(0)
+            };
+            if refs == 0 {
+                tables.blobs.remove(hash);
+            }
+            Ok(refs)
+        })
+    }
+
+    async fn blobs(&self) -> Result<Vec<Blob>, DatabaseError> {
+        Ok(self.tables.lock().unwrap().blobs.values().cloned().collect())
+    }
+
+    async fn set_blob(&self, blob: Blob) -> Result<(), DatabaseError> {
+        self.write(|tables| {
+            if blob.refs <= 0 {
+                tables.blobs.remove(&blob.hash);
+            } else {
+                tables.blobs.insert(blob.hash.clone(), blob);
+            }
+            Ok(())
+        })
+    }
+
+    async fn insert_version(&self, version: Version) -> Result<(), DatabaseError> {
+        self.write(|tables| {
+            let key = (version.path.clone(), version.number);
+            if tables.versions.contains_key(&key) {
+                return Err(DatabaseError::Duplicate(version.path))
+            }
+            tables.versions.insert(key, version);
+            Ok(())
+        })
+    }
+
+    async fn versions(&self, dir: &str) -> Result<Vec<Version>, DatabaseError> {
+        let prefix = format!("{}/", dir);
+        Ok(self.tables.lock().unwrap().versions.values()
+            .filter(|v| dir.is_empty() || v.path == dir || v.path.starts_with(&prefix))
+            .cloned()
+            .collect())
+    }
+
+    async fn delete_version(&self, path: &str, number: u64) -> Result<bool, DatabaseError> {
+        self.write(|tables| Ok(tables.versions.remove(&(path.to_string(), number)).is_some()))
+    }
+
+    async fn rename_versions(&self, from: &str, to: &str) -> Result<u64, DatabaseError> {
+        self.write(|tables| {
+            let keys: Vec<(String, u64)> = tables.versions.keys().filter(|(p, _)| p == from).cloned().collect();
+            let last = tables.versions.keys().filter(|(p, _)| p == to).map(|(_, n)| *n).max().unwrap_or(0);
+            for (i, key) in keys.iter().enumerate() {
+                let mut v = tables.versions.remove(key).unwrap();
+                v.path = to.to_string();
+                v.number = last + 1 + i as u64;
+                tables.versions.insert((v.path.clone(), v.number), v);
+            }
+            Ok(keys.len() as u64)
+        })
+    }
+
+    async fn insert_trash(&self, trashed: Trashed) -> Result<(), DatabaseError> {
+        self.write(|tables| {
+            let key = (trashed.path.clone(), trashed.number);
+            if tables.trash.contains_key(&key) {
+                return Err(DatabaseError::Duplicate(trashed.path))
+            }
+            tables.trash.insert(key, trashed);
+            Ok(())
+        })
+    }
+
+    async fn trash(&self, dir: &str) -> Result<Vec<Trashed>, DatabaseError> {
+        let prefix = format!("{}/", dir);
+        Ok(self.tables.lock().unwrap().trash.values()
+            .filter(|t| dir.is_empty() || t.path == dir || t.path.starts_with(&prefix))
+            .cloned()
+            .collect())
+    }
+
+    async fn delete_trash(&self, path: &str, number: u64) -> Result<bool, DatabaseError> {
+        self.write(|tables| Ok(tables.trash.remove(&(path.to_string(), number)).is_some()))
+    }
+
+    async fn set_tombstone(&self, tombstone: Tombstone) -> Result<(), DatabaseError> {
+        self.write(|tables| {
+            tables.tombstones.insert(tombstone.path.clone(), tombstone);
+            Ok(())
+        })
+    }
+
+    async fn tombstones(&self, dir: &str) -> Result<Vec<Tombstone>, DatabaseError> {
+        let prefix = format!("{}/", dir);
+        Ok(self.tables.lock().unwrap().tombstones.values()
+            .filter(|t| dir.is_empty() || t.path == dir || t.path.starts_with(&prefix))
+            .cloned()
+            .collect())
+    }
+
+    async fn insert_snapshot(&self, snapshot: Snapshot) -> Result<(), DatabaseError> {
+        self.write(|tables| {
+            if tables.snapshots.contains_key(&snapshot.name) {
+                return Err(DatabaseError::Duplicate(snapshot.name))
+            }
+            tables.snapshots.insert(snapshot.name.clone(), snapshot);
+            Ok(())
+        })
+    }
+
+    async fn get_snapshot(&self, name: &str) -> Result<Option<Snapshot>, DatabaseError> {
+        Ok(self.tables.lock().unwrap().snapshots.get(name).cloned())
+    }
+
+    async fn snapshots(&self) -> Result<Vec<Snapshot>, DatabaseError> {
+        let mut snapshots: Vec<Snapshot> = self.tables.lock().unwrap().snapshots.values().cloned().collect();
+        snapshots.sort_by_key(|s| s.time);
+        Ok(snapshots)
+    }
+
+    async fn delete_snapshot(&self, name: &str) -> Result<bool, DatabaseError> {
+        self.write(|tables| Ok(tables.snapshots.remove(name).is_some()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use super::*;
+    use crate::db::Chunk;
+    use crate::testutil::TempDir;
+
+    fn file(path: &str, hash: &str) -> RemoteFile {
+        RemoteFile::new(path.to_string(), hash.to_string(), 4).with_chunks(vec![Chunk::plain(hash.to_string(), 4)])
+    }
+
+    fn version(path: &str, number: u64) -> Version {
+        Version {
+            path: path.to_string(),
+            number,
+            hash: format!("{}-{}", path, number),
+            size: 4,
+            chunks: vec![],
+            time: number,
+            device: "test".to_string()
+        }
+    }
+
+    #[test]
+    fn files_round_trip() {
+        let dir = TempDir::new();
+        let path = dir.join("index.json");
+        let index = LocalIndex::open(&path).unwrap();
+        block_on(async {
+            index.insert(file("a", "h1")).await.unwrap();
+            index.insert(file("dir/b", "h2")).await.unwrap();
+            index.insert(file("dir2/c", "h3")).await.unwrap();
+            assert!(matches!(index.insert(file("a", "h4")).await, Err(DatabaseError::Duplicate(p)) if p == "a"));
+            assert!(index.update(file("a", "h5")).await.unwrap());
+            assert!(!index.update(file("none", "h5")).await.unwrap());
+        });
+
+        // everything reads back the same from disk
+        let index = LocalIndex::open(&path).unwrap();
+        block_on(async {
+            let a = index.get("a").await.unwrap().unwrap();
+            assert_eq!((a.hash.as_str(), a.chunks()), ("h5", vec![Chunk::plain("h5".to_string(), 4)]));
+            let dir: Vec<String> = index.list("dir").await.unwrap().into_iter().map(|f| f.path).collect();
+            assert_eq!(dir, ["dir/b"]);
+            assert_eq!(index.list("").await.unwrap().len(), 3);
+
+            assert!(index.rename("dir/b", "dir2/b").await.unwrap());
+            assert!(matches!(index.rename("a", "dir2/c").await, Err(DatabaseError::Duplicate(_))));
+            assert!(index.delete("a").await.unwrap());
+            assert!(!index.delete("a").await.unwrap());
+        });
+        let index = LocalIndex::open(&path).unwrap();
+        let paths: Vec<String> = block_on(index.list("")).unwrap().into_iter().map(|f| f.path).collect();
+        assert_eq!(paths, ["dir2/b", "dir2/c"]);
+    }
+
+    #[test]
+    fn shared_file() {
+        let dir = TempDir::new();
+        let path = dir.join("index.json");
+        let (one, two) = (LocalIndex::open(&path).unwrap(), LocalIndex::open(&path).unwrap());
+        block_on(async {
+            one.insert(file("a", "h1")).await.unwrap();
+            two.insert(file("b", "h2")).await.unwrap();
+            assert_eq!(one.blob_ref("x", 10).await.unwrap(), 1);
+            assert_eq!(two.blob_ref("x", 10).await.unwrap(), 2);
+            // a change failing on what the other one wrote keeps the tables as they were
+            assert!(matches!(one.insert(file("b", "h3")).await, Err(DatabaseError::Duplicate(_))));
+            assert_eq!(one.list("").await.unwrap().len(), 2);
+        });
+        let paths: Vec<String> = block_on(LocalIndex::open(&path).unwrap().list("")).unwrap().into_iter().map(|f| f.path).collect();
+        assert_eq!(paths, ["a", "b"]);
+    }
+
+    #[test]
+    fn blob_refs() {
+        let dir = TempDir::new();
+        let path = dir.join("index.json");
+        let index = LocalIndex::open(&path).unwrap();
+        block_on(async {
+            assert_eq!(index.blob_ref("x", 10).await.unwrap(), 1);
+            assert_eq!(index.blob_ref("x", 10).await.unwrap(), 2);
+            assert_eq!(index.blob_unref("x").await.unwrap(), 1);
+        });
+        let index = LocalIndex::open(&path).unwrap();
+        block_on(async {
+            let blobs = index.blobs().await.unwrap();
+            assert_eq!((blobs[0].hash.as_str(), blobs[0].size, blobs[0].refs), ("x", 10, 1));
+            assert_eq!(index.blob_unref("x").await.unwrap(), 0);
+            assert!(index.blobs().await.unwrap().is_empty());
+            assert_eq!(index.blob_unref("x").await.unwrap(), 0);
+        });
+    }
+
+    #[test]
+    fn versions_round_trip() {
+        let dir = TempDir::new();
+        let path = dir.join("index.json");
+        let index = LocalIndex::open(&path).unwrap();
+        block_on(async {
+            index.insert_version(version("a", 1)).await.unwrap();
+            index.insert_version(version("a", 2)).await.unwrap();
+            index.insert_version(version("b", 1)).await.unwrap();
+            assert!(matches!(index.insert_version(version("a", 2)).await, Err(DatabaseError::Duplicate(_))));
+        });
+        let index = LocalIndex::open(&path).unwrap();
+        block_on(async {
+            // moved versions come after the ones already there, in order
+            assert_eq!(index.rename_versions("a", "b").await.unwrap(), 2);
+            let versions: Vec<(String, u64, String)> = index.versions("").await.unwrap().into_iter()
+                .map(|v| (v.path, v.number, v.hash))
+                .collect();
+            assert_eq!(versions, [
+                ("b".to_string(), 1, "b-1".to_string()),
+                ("b".to_string(), 2, "a-1".to_string()),
+                ("b".to_string(), 3, "a-2".to_string())
+            ]);
+            assert!(index.delete_version("b", 2).await.unwrap());
+            assert!(!index.delete_version("b", 2).await.unwrap());
+        });
+    }
+}
@@ -1,41 +1,71 @@
 mod file;
+mod index;
+mod mongo;
+mod local;
+mod encrypted;
 
-pub use file::RemoteFile;
+pub use file::{RemoteFile, Blob, Chunk, Snapshot, Tombstone, Trashed, Version};
+pub use index::Index;
+pub use mongo::MongoIndex;
+pub use local::LocalIndex;
+pub use encrypted::EncryptedIndex;
+
+use std::sync::Arc;
+use crate::crypto::{Cipher, CryptoError};
 
-use mongodb::{Collection, Database as MongoDb, IndexModel};
-use mongodb::bson::doc;
-use mongodb::options::IndexOptions;
-use mongodb::error::Error;
 use crate::fs::state::{Diff, Remote, Sync};
-use crate::fs::{File, FileSuccess, SyncState, ToRemoteFile, Split, Upload};
+use crate::fs::{File, FileSuccess, SyncState, ToRemoteFile, Upload};
+use log::debug;
 
+#[derive(Debug)]
+pub enum DatabaseError {
+    MongoDB(mongodb::error::Error),
+    IO(std::io::Error),
+    /// A record with this path, or a snapshot with this name, already exists.
+    Duplicate(String),
+    /// An encrypted record that can't be read.
+    Crypto(CryptoError)
+}
+
+impl From<CryptoError> for DatabaseError {
+    fn from(value: CryptoError) -> Self {
+        Self::Crypto(value)
+    }
+}
+
+impl From<mongodb::error::Error> for DatabaseError {
+    fn from(value: mongodb::error::Error) -> Self {
+        Self::MongoDB(value)
+    }
+}
+
+impl From<std::io::Error> for DatabaseError {
+    fn from(value: std::io::Error) -> Self {
+        Self::IO(value)
+    }
+}
+
+/// Typed access to the records of an `Index`.
 #[derive(Debug)]
 pub struct Database {
-    #[allow(dead_code)]
-    db: MongoDb,
-    files: Collection<RemoteFile>
+    index: Box<dyn Index>
 }
 
 impl Database {
-    pub async fn connect(db: MongoDb) -> Result<Self, Error> {
-        let files = db.collection::<RemoteFile>("files");
-        files.create_index(
-            IndexModel::builder()
-                .options(IndexOptions::builder()
-                    .unique(true)
-                    .build())
-                .keys(doc! {"path": 1})
-                .build(),
-            None
-        ).await?;
-        Ok(Self { db, files })
+    pub fn new(index: impl Index + 'static) -> Self {
+        Self { index: Box::new(index) }
+    }
+
+    /// Keep paths and hashes of the records encrypted with `cipher` from now on.
+    pub fn encrypted(self, cipher: Arc<Cipher>) -> Self {
+        Self { index: Box::new(EncryptedIndex::new(self.index, cipher)) }
     }
 
-    pub async fn get_file_path(&self, path: impl ToString) -> Result<Option<File<Remote>>, Error> {
-        Ok(self.files.find_one(doc! {"path": path.to_string()}, None).await?.map(|f| f.to_local()))
+    pub async fn get_file_path(&self, path: impl ToString) -> Result<Option<File<Remote>>, DatabaseError> {
+        Ok(self.index.get(&path.to_string()).await?.map(|f| f.to_local()))
     }
 
-    pub async fn get_file_file<'a>(&self, file: impl Upload + 'a) -> Result<FileSuccess<SyncState, (), Box<dyn Upload + 'a>>, (Error, impl Upload)> {
+    pub async fn get_file_file<'a>(&self, file: impl Upload + 'a) -> Result<FileSuccess<SyncState, (), Box<dyn Upload + 'a>>, (DatabaseError, impl Upload)> {
         match self.get_file_path(&file.path()).await {
             Ok(r) => Ok(match r {
                 Some(f) => FileSuccess::Yes(file.attach_remote(f)),
@@ -45,40 +75,181 @@ impl Database {
         }
     }
 
+    /// List all the remote files inside directory `dir` (or `dir` itself, if it's a file), an
+    /// empty `dir` lists everything.
+    pub async fn list(&self, dir: &str) -> Result<Vec<File<Remote>>, DatabaseError> {
+        Ok(self.index.list(dir).await?.into_iter().map(|f| f.to_local()).collect())
+    }
+
+    /// The record of `path` as stored, with its chunks.
+    pub async fn record(&self, path: impl ToString) -> Result<Option<RemoteFile>, DatabaseError> {
+        self.index.get(&path.to_string()).await
+    }
+
+    /// Records inside directory `dir` written by older versions of bdrive, before contents were
+    /// split into blobs: their content may still be stored at the path of the file.
+    pub async fn legacy_records(&self, dir: &str) -> Result<Vec<RemoteFile>, DatabaseError> {
+        Ok(self.index.list(dir).await?.into_iter().filter(|f| f.chunks.is_none()).collect())
+    }
+
     /// Check if remote path exists
-    pub async fn exists(&mut self, path: impl ToString) -> mongodb::error::Result<bool> {
-        self.files.find(doc! {"path": path.to_string()}, None).await.map(|r| r.deserialize_current().is_ok())
-    }
-
-    /// Sync a File<Diff> with database, sets remote hash to local hash
-    pub async fn push(&mut self, f: File<Diff>) -> FileSuccess<File<Sync>, mongodb::error::Error, File<Diff>> {
-        let (local, remote) = f.split();
-        println!("updating {:?} to {:?} in db...", local, remote);
-        let rfile = local.to_remote_file();
-        match self.files.update_one(
-            doc! {"path": rfile.path},
-            doc! {"$set": {
-                "size": rfile.size as u32,
-                "hash": rfile.hash
-            }},
-            None
-        ).await {
-            Ok(_) => FileSuccess::Yes(local.upcast()),
-            Err(e) => FileSuccess::No(e, match local.attach_remote(remote) {
-                SyncState::Sync(_) => panic!(),
-                SyncState::Diff(d) => d
-            })
+    pub async fn exists(&self, path: impl ToString) -> Result<bool, DatabaseError> {
+        Ok(self.index.get(&path.to_string()).await?.is_some())
+    }
+
+    /// Blobs making up the content of `path`, empty if there's no such file.
+    pub async fn chunks(&self, path: impl ToString) -> Result<Vec<Chunk>, DatabaseError> {
+        Ok(self.index.get(&path.to_string()).await?.map(|f| f.chunks()).unwrap_or_default())
+    }
+
+    /// Paths and chunks of all the remote files inside directory `dir`, like [`Database::list`].
+    pub async fn list_chunks(&self, dir: &str) -> Result<Vec<(String, Vec<Chunk>)>, DatabaseError> {
+        Ok(self.index.list(dir).await?.into_iter().map(|f| {
+            let chunks = f.chunks();
+            (f.path, chunks)
+        }).collect())
+    }
+
+    /// Sync a File<Diff> with database, sets remote hash to local hash and the content to `chunks`.
+    /// The record is replaced at once, on failure it still describes the previous content.
+    pub async fn push(&self, f: File<Diff>, chunks: Vec<Chunk>) -> FileSuccess<File<Sync>, DatabaseError, File<Diff>> {
+        debug!("updating {:?} in db...", f);
+        let record = f.to_remote_file().with_chunks(chunks);
+        let result = match self.index.update(record.clone()).await {
+            // the record vanished in the meantime, add it back
+            Ok(false) => self.index.insert(record).await,
+            r => r.map(|_| ())
+        };
+        match result {
+            Ok(()) => FileSuccess::Yes(f.upcast()),
+            Err(e) => FileSuccess::No(e, f)
         }
     }
 
-    /// Add a `dyn Upload` to db, convert it to `File<Sync>` on success
-    pub async fn create<'a>(&mut self, f: Box<dyn Upload + 'a>) -> FileSuccess<File<Sync>, mongodb::error::Error, Box<dyn Upload + 'a>> {
-        let rfile = f.to_remote_file();
-        
-        match self.files.insert_one(
-            rfile,
-            None
-        ).await {
+    /// Remove the record of `path`, returns whether there was one.
+    pub async fn delete(&self, path: impl ToString) -> Result<bool, DatabaseError> {
+        self.index.delete(&path.to_string()).await
+    }
+
+    /// Move the record of `from` to `to`, returns whether there was one.
+    pub async fn rename(&self, from: impl ToString, to: impl ToString) -> Result<bool, DatabaseError> {
+        self.index.rename(&from.to_string(), &to.to_string()).await
+    }
+
+    /// Add a record for content that's already stored, like a copy of another file.
+    pub async fn create_remote(&self, f: &File<Remote>, chunks: Vec<Chunk>) -> Result<(), DatabaseError> {
+        self.index.insert(f.to_remote_file().with_chunks(chunks)).await
+    }
+
+    /// Point the record of `f` to `chunks`, adding it if missing.
+    pub async fn replace_remote(&self, f: &File<Remote>, chunks: Vec<Chunk>) -> Result<(), DatabaseError> {
+        let record = f.to_remote_file().with_chunks(chunks);
+        match self.index.update(record.clone()).await? {
+            true => Ok(()),
+            false => self.index.insert(record).await
+        }
+    }
+
+    /// Count a new reference to the blob `hash`, returns the updated count.
+    pub async fn ref_blob(&self, hash: &str, size: u64) -> Result<u64, DatabaseError> {
+        self.index.blob_ref(hash, size).await
+    }
+
+    /// Drop a reference to the blob `hash`, returns the updated count.
+    pub async fn unref_blob(&self, hash: &str) -> Result<u64, DatabaseError> {
+        self.index.blob_unref(hash).await
+    }
+
+    /// Every blob record, with its reference count.
+    pub async fn blobs(&self) -> Result<Vec<Blob>, DatabaseError> {
+        self.index.blobs().await
+    }
+
+    /// Replace the record of `blob.hash`, removing it if it has no references.
+    pub async fn set_blob(&self, blob: Blob) -> Result<(), DatabaseError> {
+        self.index.set_blob(blob).await
+    }
+
+    /// Versions of the file `path`, oldest first.
+    pub async fn versions(&self, path: impl ToString) -> Result<Vec<Version>, DatabaseError> {
+        let path = path.to_string();
+        Ok(self.index.versions(&path).await?.into_iter().filter(|v| v.path == path).collect())
+    }
+
+    /// Versions of all the files inside directory `dir`, like [`Database::list`].
+    pub async fn list_versions(&self, dir: &str) -> Result<Vec<Version>, DatabaseError> {
+        self.index.versions(dir).await
+    }
+
+    pub async fn add_version(&self, version: Version) -> Result<(), DatabaseError> {
+        self.index.insert_version(version).await
+    }
+
+    /// Remove version `number` of `path`, returns whether there was one.
+    pub async fn delete_version(&self, path: impl ToString, number: u64) -> Result<bool, DatabaseError> {
+        self.index.delete_version(&path.to_string(), number).await
+    }
+
+    /// Move the versions of `from` to `to`, numbered in order after the versions `to` already
+    /// has, returns how many were moved.
+    pub async fn rename_versions(&self, from: impl ToString, to: impl ToString) -> Result<u64, DatabaseError> {
+        self.index.rename_versions(&from.to_string(), &to.to_string()).await
+    }
+
+    /// Records of all the remote files inside directory `dir`, with their chunks, like
+    /// [`Database::list`].
+    pub async fn records(&self, dir: &str) -> Result<Vec<RemoteFile>, DatabaseError> {
+        Ok(self.index.list(dir).await?.into_iter().map(|f| {
+            let chunks = f.chunks();
+            f.with_chunks(chunks)
+        }).collect())
+    }
+
+    /// Deleted files inside directory `dir`, like [`Database::list`], ordered by path and number.
+    pub async fn trash(&self, dir: &str) -> Result<Vec<Trashed>, DatabaseError> {
+        self.index.trash(dir).await
+    }
+
+    pub async fn add_trash(&self, trashed: Trashed) -> Result<(), DatabaseError> {
+        self.index.insert_trash(trashed).await
+    }
+
+    /// Remove entry `number` of `path` from the trash, returns whether there was one.
+    pub async fn delete_trash(&self, path: impl ToString, number: u64) -> Result<bool, DatabaseError> {
+        self.index.delete_trash(&path.to_string(), number).await
+    }
+
+    /// Record the deletion of a path, replacing any previous tombstone of it.
+    pub async fn set_tombstone(&self, tombstone: Tombstone) -> Result<(), DatabaseError> {
+        self.index.set_tombstone(tombstone).await
+    }
+
+    /// Tombstones of the paths inside directory `dir`, like [`Database::list`].
+    pub async fn tombstones(&self, dir: &str) -> Result<Vec<Tombstone>, DatabaseError> {
+        self.index.tombstones(dir).await
+    }
+
+    pub async fn add_snapshot(&self, snapshot: Snapshot) -> Result<(), DatabaseError> {
+        self.index.insert_snapshot(snapshot).await
+    }
+
+    pub async fn get_snapshot(&self, name: &str) -> Result<Option<Snapshot>, DatabaseError> {
+        self.index.get_snapshot(name).await
+    }
+
+    /// Every snapshot, oldest first.
+    pub async fn snapshots(&self) -> Result<Vec<Snapshot>, DatabaseError> {
+        self.index.snapshots().await
+    }
+
+    /// Remove the snapshot `name`, returns whether there was one.
+    pub async fn delete_snapshot(&self, name: &str) -> Result<bool, DatabaseError> {
+        self.index.delete_snapshot(name).await
+    }
+
+    /// Add a `dyn Upload` made of `chunks` to db, convert it to `File<Sync>` on success
+    pub async fn create<'a>(&self, f: Box<dyn Upload + 'a>, chunks: Vec<Chunk>) -> FileSuccess<File<Sync>, DatabaseError, Box<dyn Upload + 'a>> {
+        match self.index.insert(f.to_remote_file().with_chunks(chunks)).await {
             Ok(_) => FileSuccess::Yes(f.upcast()),
             Err(e) => FileSuccess::No(e, f)
         }
@@ -0,0 +1,281 @@
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use mongodb::{Collection, Database as MongoDb, IndexModel};
+use mongodb::bson::doc;
+use mongodb::error::{Error, ErrorKind, WriteFailure};
+use mongodb::options::{FindOneAndUpdateOptions, FindOneOptions, FindOptions, IndexOptions, ReplaceOptions, ReturnDocument};
+use super::{Blob, DatabaseError, Index, RemoteFile, Snapshot, Tombstone, Trashed, Version};
+
+/// Index kept in the `files`, `blobs`, `versions`, `trash`, `tombstones` and `snapshots`
+/// collections of a MongoDB database.
+#[derive(Debug)]
+pub struct MongoIndex {
+    #[allow(dead_code)]
+    db: MongoDb,
+    files: Collection<RemoteFile>,
+    blobs: Collection<Blob>,
+    versions: Collection<Version>,
+    snapshots: Collection<Snapshot>,
+    trash: Collection<Trashed>,
+    tombstones: Collection<Tombstone>
+}
+
+impl MongoIndex {
+    pub async fn connect(db: MongoDb) -> Result<Self, mongodb::error::Error> {
+        let files = db.collection::<RemoteFile>("files");
+        files.create_index(
+            IndexModel::builder()
+                .options(IndexOptions::builder()
+                    .unique(true)
+                    .build())
+                .keys(doc! {"path": 1})
+                .build(),
+            None
+        ).await?;
+        let blobs = db.collection::<Blob>("blobs");
+        blobs.create_index(
+            IndexModel::builder()
+                .options(IndexOptions::builder()
+                    .unique(true)
+                    .build())
+                .keys(doc! {"hash": 1})
+                .build(),
+            None
+        ).await?;
+        let versions = db.collection::<Version>("versions");
+        versions.create_index(
+            IndexModel::builder()
+                .options(IndexOptions::builder()
+                    .unique(true)
+                    .build())
+                .keys(doc! {"path": 1, "number": 1})
+                .build(),
+            None
+        ).await?;
+        let snapshots = db.collection::<Snapshot>("snapshots");
+        snapshots.create_index(
+            IndexModel::builder()
+                .options(IndexOptions::builder()
+                    .unique(true)
+                    .build())
+                .keys(doc! {"name": 1})
+                .build(),
+            None
+        ).await?;
+        let trash = db.collection::<Trashed>("trash");
+        trash.create_index(
+            IndexModel::builder()
+                .options(IndexOptions::builder()
+                    .unique(true)
+                    .build())
+                .keys(doc! {"path": 1, "number": 1})
+                .build(),
+            None
+        ).await?;
+        let tombstones = db.collection::<Tombstone>("tombstones");
+        tombstones.create_index(
+            IndexModel::builder()
+                .options(IndexOptions::builder()
+                    .unique(true)
+                    .build())
+                .keys(doc! {"path": 1})
+                .build(),
+            None
+        ).await?;
+        Ok(Self { db, files, blobs, versions, snapshots, trash, tombstones })
+    }
+}
+
+#[async_trait]
+impl Index for MongoIndex {
+    async fn get(&self, path: &str) -> Result<Option<RemoteFile>, DatabaseError> {
+        Ok(self.files.find_one(doc! {"path": path}, None).await?)
+    }
+
+    async fn list(&self, dir: &str) -> Result<Vec<RemoteFile>, DatabaseError> {
+        Ok(self.files.find(dir_filter(dir), None).await?.try_collect().await?)
+    }
+
+    async fn insert(&self, file: RemoteFile) -> Result<(), DatabaseError> {
+        let path = file.path.clone();
+        self.files.insert_one(file, None).await.map_err(duplicate(&path))?;
+        Ok(())
+    }
+
+    async fn update(&self, file: RemoteFile) -> Result<bool, DatabaseError> {
+        Ok(self.files.replace_one(doc! {"path": &file.path}, &file, None).await?.matched_count > 0)
+    }
+
+    async fn delete(&self, path: &str) -> Result<bool, DatabaseError> {
+        Ok(self.files.delete_one(doc! {"path": path}, None).await?.deleted_count > 0)
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<bool, DatabaseError> {
+        Ok(self.files.update_one(
+            doc! {"path": from},
+            doc! {"$set": {"path": to}},
+            None
+        ).await.map_err(duplicate(to))?.matched_count > 0)
+    }
+
+    async fn blob_ref(&self, hash: &str, size: u64) -> Result<u64, DatabaseError> {
+        let blob = self.blobs.find_one_and_update(
+            doc! {"hash": hash},
+            doc! {"$inc": {"refs": 1_i64}, "$setOnInsert": {"size": size as i64}},
+            FindOneAndUpdateOptions::builder()
+                .upsert(true)
+                .return_document(ReturnDocument::After)
+                .build()
+        ).await?;
+        Ok(blob.map(|b| b.refs.max(0) as u64).unwrap_or(1))
+    }
+
+    async fn blob_unref(&self, hash: &str) -> Result<u64, DatabaseError> {
+        let blob = self.blobs.find_one_and_update(
+            doc! {"hash": hash},
+            doc! {"$inc": {"refs": -1_i64}},
+            FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build()
+        ).await?;
+        match blob {
+            Some(b) if b.refs > 0 => Ok(b.refs as u64),
+            Some(_) => {
+                self.blobs.delete_one(doc! {"hash": hash, "refs": {"$lte": 0_i64}}, None).await?;
+                Ok(0)
+            }
+            None => Ok(0)
+        }
+    }
+
+    async fn blobs(&self) -> Result<Vec<Blob>, DatabaseError> {
+        Ok(self.blobs.find(doc! {}, None).await?.try_collect().await?)
+    }
+
+    async fn set_blob(&self, blob: Blob) -> Result<(), DatabaseError> {
+        if blob.refs <= 0 {
+            self.blobs.delete_one(doc! {"hash": &blob.hash}, None).await?;
+        } else {
+            self.blobs.replace_one(
+                doc! {"hash": &blob.hash},
+                &blob,
+                ReplaceOptions::builder().upsert(true).build()
+            ).await?;
+        }
+        Ok(())
+    }
+
+    async fn insert_version(&self, version: Version) -> Result<(), DatabaseError> {
+        let path = version.path.clone();
+        self.versions.insert_one(version, None).await.map_err(duplicate(&path))?;
+        Ok(())
+    }
+
+    async fn versions(&self, dir: &str) -> Result<Vec<Version>, DatabaseError> {
+        let options = FindOptions::builder().sort(doc! {"path": 1, "number": 1}).build();
+        Ok(self.versions.find(dir_filter(dir), options).await?.try_collect().await?)
+    }
+
+    async fn delete_version(&self, path: &str, number: u64) -> Result<bool, DatabaseError> {
+        Ok(self.versions.delete_one(doc! {"path": path, "number": number as i64}, None).await?.deleted_count > 0)
+    }
+
+    async fn rename_versions(&self, from: &str, to: &str) -> Result<u64, DatabaseError> {
+        let last = self.versions.find_one(
+            doc! {"path": to},
+            FindOneOptions::builder().sort(doc! {"number": -1}).build()
+        ).await?.map(|v| v.number).unwrap_or(0);
+        let options = FindOptions::builder().sort(doc! {"number": 1}).build();
+        let moved: Vec<Version> = self.versions.find(doc! {"path": from}, options).await?.try_collect().await?;
+        for (i, v) in moved.iter().enumerate() {
+            self.versions.update_one(
+                doc! {"path": from, "number": v.number as i64},
+                doc! {"$set": {"path": to, "number": (last + 1 + i as u64) as i64}},
+                None
+            ).await.map_err(duplicate(to))?;
+        }
+        Ok(moved.len() as u64)
+    }
+
+    async fn insert_trash(&self, trashed: Trashed) -> Result<(), DatabaseError> {
+        let path = trashed.path.clone();
+        self.trash.insert_one(trashed, None).await.map_err(duplicate(&path))?;
+        Ok(())
+    }
+
+    async fn trash(&self, dir: &str) -> Result<Vec<Trashed>, DatabaseError> {
+        let options = FindOptions::builder().sort(doc! {"path": 1, "number": 1}).build();
+        Ok(self.trash.find(dir_filter(dir), options).await?.try_collect().await?)
+    }
+
+    async fn delete_trash(&self, path: &str, number: u64) -> Result<bool, DatabaseError> {
+        Ok(self.trash.delete_one(doc! {"path": path, "number": number as i64}, None).await?.deleted_count > 0)
+    }
+
+    async fn set_tombstone(&self, tombstone: Tombstone) -> Result<(), DatabaseError> {
+        self.tombstones.replace_one(
+            doc! {"path": &tombstone.path},
+            &tombstone,
+            ReplaceOptions::builder().upsert(true).build()
+        ).await?;
+        Ok(())
+    }
+
+    async fn tombstones(&self, dir: &str) -> Result<Vec<Tombstone>, DatabaseError> {
+        Ok(self.tombstones.find(dir_filter(dir), None).await?.try_collect().await?)
+    }
+
+    async fn insert_snapshot(&self, snapshot: Snapshot) -> Result<(), DatabaseError> {
+        let name = snapshot.name.clone();
+        self.snapshots.insert_one(snapshot, None).await.map_err(duplicate(&name))?;
+        Ok(())
+    }
+
+    async fn get_snapshot(&self, name: &str) -> Result<Option<Snapshot>, DatabaseError> {
+        Ok(self.snapshots.find_one(doc! {"name": name}, None).await?)
+    }
+
+    async fn snapshots(&self) -> Result<Vec<Snapshot>, DatabaseError> {
+        let options = FindOptions::builder().sort(doc! {"time": 1}).build();
+        Ok(self.snapshots.find(doc! {}, options).await?.try_collect().await?)
+    }
+
+    async fn delete_snapshot(&self, name: &str) -> Result<bool, DatabaseError> {
+        Ok(self.snapshots.delete_one(doc! {"name": name}, None).await?.deleted_count > 0)
+    }
+}
+
+/// Code of the write error raised when a unique index is violated.
+const DUPLICATE_KEY: i32 = 11000;
+
+/// Map a violation of a unique index to [`DatabaseError::Duplicate`] of `key`, the path or name
+/// that was already taken, and any other error as it is.
+fn duplicate(key: &str) -> impl FnOnce(Error) -> DatabaseError + '_ {
+    move |e| {
+        let taken = matches!(&*e.kind, ErrorKind::Write(WriteFailure::WriteError(w)) if w.code == DUPLICATE_KEY);
+        match taken {
+            true => DatabaseError::Duplicate(key.to_string()),
+            false => e.into()
+        }
+    }
+}
+
+/// Filter matching the paths inside directory `dir`, or `dir` itself.
+fn dir_filter(dir: &str) -> mongodb::bson::Document {
+    if dir.is_empty() {
+        doc! {}
+    } else {
+        doc! {"path": {"$regex": format!("^{}(/|$)", regex_escape(dir))}}
+    }
+}
+
+fn regex_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "\\^$.|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
@@ -0,0 +1,20 @@
+use std::io::Read;
+use fastcdc::v2020::StreamCDC;
+use hex::ToHex;
+use ring::digest::{digest, SHA256};
+use crate::db::Chunk;
+
+/// Bounds of the content defined chunks: boundaries depend only on the surrounding bytes, so an
+/// edit only changes the chunks it touches.
+pub const MIN_CHUNK: u32 = 256 << 10;
+pub const AVG_CHUNK: u32 = 1 << 20;
+pub const MAX_CHUNK: u32 = 4 << 20;
+
+/// Split the content of `reader` in chunks, yielding each with its data.
+pub fn chunks<R: Read>(reader: R) -> impl Iterator<Item = std::io::Result<(Chunk, Vec<u8>)>> {
+    StreamCDC::new(reader, MIN_CHUNK, AVG_CHUNK, MAX_CHUNK).map(|c| {
+        let c = c?;
+        let chunk = Chunk::plain(digest(&SHA256, &c.data).encode_hex(), c.length as u64);
+        Ok((chunk, c.data))
+    })
+}
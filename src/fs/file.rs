@@ -84,13 +84,9 @@ impl File<Local> {
     }
 }
 
-impl File<LocalHashed> {
-    // create-public, we shouldn't assume the remote is equal to local.
-    pub(crate) fn upcast(self) -> File<Sync> {
-        File {
-            path: self.path,
-            state: Sync { id: self.state.local }
-        }
+impl File<Remote> {
+    pub fn remote_identity(&self) -> Identity {
+        self.state.remote.clone()
     }
 }
 
@@ -106,6 +102,12 @@ impl ToRemoteFile for File<Remote> {
     }
 }
 
+impl File<Sync> {
+    pub fn identity(&self) -> Identity {
+        self.state.id.clone()
+    }
+}
+
 impl ToRemoteFile for File<Sync> {
     fn to_remote_file(&self) -> RemoteFile {
         RemoteFile::new(self.path.to_string(), self.state.id.hash(), self.state.id.size())
@@ -180,6 +182,12 @@ impl ToRemoteFile for File<Diff> {
     }
 }
 
+impl File<Diff> {
+    pub fn remote_identity(&self) -> Identity {
+        self.state.remote.clone()
+    }
+}
+
 impl Upload for File<Diff> {
     fn local_identity(&self) -> Identity {
         self.state.local.clone()
@@ -1,4 +1,4 @@
-use std::io::Read;
+use std::io::{Read, Write};
 use ring::digest::{Context, Digest, SHA256};
 
 pub fn hash_reader<R: Read>(mut reader: R) -> std::io::Result<Digest> {
@@ -12,4 +12,35 @@ pub fn hash_reader<R: Read>(mut reader: R) -> std::io::Result<Digest> {
         context.update(&buffer[..count]);
     }
     Ok(context.finish())
-}
\ No newline at end of file
+}
+
+/// Sink feeding everything written into a SHA-256 digest, for contents that are written rather
+/// than read.
+pub struct HashWriter(Context);
+
+impl HashWriter {
+    pub fn new() -> Self {
+        Self(Context::new(&SHA256))
+    }
+
+    pub fn finish(self) -> Digest {
+        self.0.finish()
+    }
+}
+
+impl Default for HashWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -1,3 +1,4 @@
+#[allow(dead_code)]
 pub trait Inode {
 
 }
\ No newline at end of file
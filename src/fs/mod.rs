@@ -2,9 +2,11 @@ mod file;
 mod inode;
 mod file_success;
 mod hash;
+mod chunk;
 
 pub mod state;
 
 pub use file::{File, ToRemoteFile, SyncState, Upload, Split, LocalFile, BoxedUpload};
-pub use hash::hash_reader;
+pub use hash::{hash_reader, HashWriter};
+pub use chunk::chunks;
 pub use file_success::FileSuccess;
\ No newline at end of file
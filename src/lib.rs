@@ -3,5 +3,11 @@
 pub mod db;
 pub mod fs;
 pub mod ssh;
+pub mod storage;
+pub mod crypto;
+pub mod compress;
 pub mod conf;
-pub mod bdrive;
\ No newline at end of file
+pub mod bdrive;
+
+#[cfg(test)]
+mod testutil;
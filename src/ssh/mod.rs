@@ -1,24 +1,31 @@
+mod pool;
+
+pub use pool::SSHPool;
+
 use std::fmt::{Debug, Formatter};
-use ssh2::{Error, Session, Sftp};
-use tokio::net::TcpStream;
-use std::fs::File as StdFile;
-use std::io::{BufReader, BufWriter};
-use std::fmt::Write;
-use std::path::PathBuf;
-use std::time::Instant;
-use indicatif::{ProgressState, ProgressStyle};
-use crate::conf::{PathError, PathsConf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use ssh2::{Error, OpenFlags, OpenType, RenameFlags, Session, Sftp};
+use std::net::TcpStream;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use crate::conf::PathError;
+use crate::storage::{stream_hash, Storage, StorageError};
+use log::{debug, info};
 
 pub struct SSHClient {
     session: Session,
-    sftp: Option<Sftp>
+    sftp: Sftp,
+    root: PathBuf,
+    /// Whether the server can hash files itself, cleared once it turns out it lacks the tools.
+    remote_hash: AtomicBool
 }
 
 #[derive(Debug)]
 pub enum SSHError {
     SSH2(Error),
     Path(String),
-    MkdirError(String)
+    MkdirError(String),
+    IO(std::io::Error)
 }
 
 impl From<PathError> for SSHError {
@@ -33,133 +40,266 @@ impl From<Error> for SSHError {
     }
 }
 
+impl From<std::io::Error> for SSHError {
+    fn from(value: std::io::Error) -> Self {
+        Self::IO(value)
+    }
+}
+
 const BUFF_SIZE: usize = 2 << 20;
 
+/// Recursively try to mkdir a folder
+fn recursive_mkdir(s: &Sftp, mut vec: Vec<String>) -> Result<Vec<String>, SSHError> {
+    debug!("recursing over {:?}", vec);
+    if vec.len() == 1 {
+        Err(SSHError::MkdirError("reached root, cannot recurse further".to_string()))
+    } else {
+        let pop = vec.pop().unwrap();
+        let join = PathBuf::from(vec.join("/"));
+        if let Err(e) = s.mkdir(&join, 0o755) {
+            if e.message() != "no such file" {
+                Err(SSHError::MkdirError(e.message().to_string()))
+            } else {
+                let mut vec = recursive_mkdir(s, vec)?;
+                // now we know for sure that all the parent exists.
+                vec.push(pop);
+                debug!("successfully created: {:?}", join);
+                s.mkdir(&join, 0o755).map_err(|e| SSHError::MkdirError(e.message().to_string()))?;
+                Ok(vec)
+            }
+        } else {
+            debug!("successfully created: {:?}", join);
+            Ok(vec)
+        }
+    }
+}
+
+fn split_path(path: &Path) -> Vec<String> {
+    path.to_str().unwrap().split('/').map(|s| s.to_string()).collect()
+}
+
 impl SSHClient {
-    pub fn new() -> Self {
-        Self { session: Session::new().unwrap(), sftp: None}
+    /// Open a session to `host` as `username`, authenticated by the ssh agent, storing
+    /// everything under the remote directory `root`. ssh2 blocks, so the handshake runs on a
+    /// thread of its own rather than on the runtime.
+    pub async fn connect(root: impl Into<PathBuf>, username: String, port: u16, host: String) -> std::io::Result<Self> {
+        let root = root.into();
+        match tokio::task::spawn_blocking(move || Self::open(root, &username, port, &host)).await {
+            Ok(client) => client,
+            Err(e) => Err(std::io::Error::other(e))
+        }
     }
 
-    pub async fn connect(
-        &mut self,
-        username: String,
-        port: u16,
-        host: String
-    ) -> std::io::Result<()> {
-        let stream = TcpStream::connect(format!("{host}:{port}")).await?;
-        self.session.set_tcp_stream(stream);
-        self.session.handshake()?;
+    fn open(root: PathBuf, username: &str, port: u16, host: &str) -> std::io::Result<Self> {
+        let mut session = Session::new()?;
+        session.set_tcp_stream(TcpStream::connect((host, port))?);
+        session.set_blocking(true);
+        session.handshake()?;
 
-        let mut agent = self.session.agent()?;
+        let mut agent = session.agent()?;
         agent.connect()?;
         agent.list_identities()?;
         let identities = agent.identities()?;
         let mut errors = vec![];
         for identity in identities {
-            match agent.userauth(&username, &identity) {
+            match agent.userauth(username, &identity) {
                 Ok(()) => {
-                    self.session.set_blocking(true);
-                    println!("creating sftp");
-                    self.sftp = Some(self.session.sftp()?);
-                    return Ok(())
+                    debug!("creating sftp");
+                    let sftp = session.sftp()?;
+                    return Ok(Self { session, sftp, root, remote_hash: AtomicBool::new(true) })
                 },
                 Err(e) => errors.push(e)
             }
         }
 
-        Err(errors.pop().unwrap())?
+        match errors.pop() {
+            Some(e) => Err(e.into()),
+            None => Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "the ssh agent has no identities"))
+        }
     }
 
     fn sftp(&self) -> &Sftp {
-        self.sftp.as_ref().unwrap()
+        &self.sftp
     }
 
-    pub fn write(&self, paths: &PathsConf, rel: String, size: u64) -> Result<(), SSHError> {
-        // todo: manage permission numbers...
-        assert!(self.session.authenticated());
-        let path = paths.absolute(&rel).unwrap();
-        let remote = paths.to_remote(&rel);
-        println!("uploading file {:?} to {:?}", paths.canonical(&rel).unwrap(), remote);
-
-
-        let mut local_reader = BufReader::with_capacity(BUFF_SIZE, StdFile::open(path).unwrap());
+    fn remote(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
 
-        // println!("request scp send...");
+    /// Run `command` on the server with `input` on its standard input, returns its exit status,
+    /// standard output and error.
+    fn exec(&self, command: &str, input: &[u8]) -> Result<(i32, String, String), SSHError> {
+        let mut channel = self.session.channel_session()?;
+        channel.exec(command)?;
+        channel.write_all(input)?;
+        channel.send_eof()?;
+        let mut out = String::new();
+        channel.read_to_string(&mut out)?;
+        let mut err = String::new();
+        channel.stderr().read_to_string(&mut err)?;
+        channel.wait_close()?;
+        Ok((channel.exit_status()?, out, err))
+    }
 
-        /// Recursively try to mkdir a folder
-        fn recursive_mkdir(s: &Sftp, mut vec: Vec<String>) -> Result<Vec<String>, SSHError> {
-            println!("recursing over {:?}", vec);
-            if vec.len() == 1 {
-                Err(SSHError::MkdirError("reached root, cannot recurse further".to_string()))
-            } else {
-                let pop = vec.pop().unwrap();
-                let join = PathBuf::from(vec.join("/"));
-                if let Err(e) = s.mkdir(&join, 0o755) {
-                    if e.message() != "no such file" {
-                        Err(SSHError::MkdirError(e.message().to_string()))
-                    } else {
-                        let mut vec = recursive_mkdir(s, vec)?;
-                        // now we know for sure that all the parent exists.
-                        vec.push(pop);
-                        println!("successfully created: {:?}", join);
-                        s.mkdir(&join, 0o755).unwrap();
-                        Ok(vec)
-                    }
-                } else {
-                    println!("successfully created: {:?}", join);
-                    Ok(vec)
+    /// Hash the files `paths` joined with `sha256sum` on the server, `None` if that isn't possible.
+    /// The paths are passed on the standard input, so there can be any number of them. Remote
+    /// hashing is only given up for the session if the server lacks the tools.
+    fn exec_hash(&self, paths: &[PathBuf]) -> Option<String> {
+        let mut input = vec![];
+        for p in paths {
+            input.extend_from_slice(p.as_os_str().as_encoded_bytes());
+            input.push(0);
+        }
+        match self.exec("xargs -0 cat -- | sha256sum", &input) {
+            Ok((0, out, err)) if err.is_empty() => {
+                let hash = out.split_whitespace().next().unwrap_or_default();
+                if hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Some(hash.to_lowercase())
                 }
+                debug!("unexpected sha256sum output: {:?}", out);
             }
+            Ok((status, _, err)) => debug!("remote hashing failed with status {}: {}", status, err.trim()),
+            Err(e) => debug!("cannot run remote hashing: {:?}", e)
         }
+        if let Ok((status, _, _)) = self.exec("command -v xargs && command -v sha256sum", &[]) {
+            if status != 0 {
+                info!("the server cannot hash files, streaming them instead");
+                self.remote_hash.store(false, Ordering::Relaxed);
+            }
+        }
+        None
+    }
 
-        let remote_file = match self.sftp.as_ref().unwrap().create(remote.as_path()) {
+    /// Rename `src` to `dst`, replacing it. Servers speaking SFTP version 3, like openssh, ignore
+    /// the overwrite flag and refuse to replace an existing file: in that case `dst` is removed
+    /// first, which leaves a short window where neither exists.
+    fn replace(&self, src: &Path, dst: &Path) -> Result<(), SSHError> {
+        match self.sftp().rename(src, dst, Some(RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE)) {
+            Ok(()) => Ok(()),
+            Err(e) if !not_found(&e) && self.sftp().stat(dst).is_ok() => {
+                debug!("cannot overwrite {:?}, removing it first", dst);
+                self.sftp().unlink(dst)?;
+                Ok(self.sftp().rename(src, dst, None)?)
+            }
+            Err(e) => Err(e.into())
+        }
+    }
+}
+
+fn not_found(e: &Error) -> bool {
+    e.message() == "no such file"
+}
+
+impl Storage for SSHClient {
+    fn append(&self, key: &str, reader: &mut dyn Read) -> Result<u64, StorageError> {
+        assert!(self.session.authenticated());
+        let remote = self.remote(key);
+        let flags = OpenFlags::WRITE | OpenFlags::CREATE;
+
+        let mut remote_file = match self.sftp().open_mode(remote.as_path(), flags, 0o644, OpenType::File) {
             Ok(f) => f,
-            Err(e) => {
-                match e.message() {
-                    "no such file" => {
-                        let remote_split: Vec<String> = remote.to_str().unwrap().split('/').map(|s| s.to_string()).collect();
-                        recursive_mkdir(self.sftp(), remote_split)?;
-                        self.sftp.as_ref().unwrap().create(remote.as_path())?
-                    }
-                    _ => Err(e)?
-                }
+            Err(e) if not_found(&e) => {
+                recursive_mkdir(self.sftp(), split_path(&remote))?;
+                self.sftp().open_mode(remote.as_path(), flags, 0o644, OpenType::File).map_err(SSHError::from)?
             }
+            Err(e) => Err(SSHError::from(e))?
         };
+        // the append flag isn't honoured by every server, write past the current end instead
+        let end = remote_file.stat().map_err(SSHError::from)?.size.unwrap_or_default();
+        remote_file.seek(SeekFrom::Start(end))?;
 
         let mut ch = BufWriter::with_capacity(BUFF_SIZE, remote_file);
+        let size = std::io::copy(reader, &mut ch)?;
+        let mut remote_file = ch.into_inner().map_err(|e| e.into_error())?;
+        if let Err(e) = remote_file.fsync() {
+            debug!("cannot fsync {:?}: {}", remote, e.message());
+        }
+        Ok(size)
+    }
 
-        let bar = indicatif::ProgressBar::new(size);
-        bar.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-            .unwrap()
-            .with_key("eta", |state: &ProgressState, w: &mut dyn Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
-            .progress_chars("#>-"));
-        let mut bar_reader = bar.wrap_read(&mut local_reader);
-
-        println!("start copying data...");
+    fn get(&self, key: &str, writer: &mut dyn Write) -> Result<u64, StorageError> {
+        assert!(self.session.authenticated());
+        let remote_file = match self.sftp().open(self.remote(key).as_path()) {
+            Ok(f) => f,
+            Err(e) if not_found(&e) => return Err(StorageError::NotFound(key.to_string())),
+            Err(e) => Err(SSHError::from(e))?
+        };
+        let mut remote_reader = BufReader::with_capacity(BUFF_SIZE, remote_file);
+        Ok(std::io::copy(&mut remote_reader, writer)?)
+    }
 
-        let start = Instant::now();
+    fn delete(&self, key: &str) -> Result<(), StorageError> {
+        match self.sftp().unlink(self.remote(key).as_path()) {
+            Ok(()) => Ok(()),
+            Err(e) if not_found(&e) => Err(StorageError::NotFound(key.to_string())),
+            Err(e) => Err(SSHError::from(e))?
+        }
+    }
 
-        std::io::copy(&mut bar_reader, &mut ch).unwrap();
-        let elapsed = start.elapsed();
-        println!("operation took {:?}", elapsed);
-        println!("operation performance: {:0.2} bytes/sec", size as f64 / elapsed.as_secs_f64());
+    fn rename(&self, from: &str, to: &str) -> Result<(), StorageError> {
+        let src = self.remote(from);
+        let dst = self.remote(to);
+        if self.sftp().stat(&src).is_err() {
+            return Err(StorageError::NotFound(from.to_string()))
+        }
+        match self.replace(&src, &dst) {
+            Err(SSHError::SSH2(e)) if not_found(&e) => {
+                recursive_mkdir(self.sftp(), split_path(&dst))?;
+                Ok(self.replace(&src, &dst)?)
+            }
+            r => Ok(r?)
+        }
+    }
 
-        Ok(())
+    fn stat(&self, key: &str) -> Result<Option<u64>, StorageError> {
+        match self.sftp().stat(self.remote(key).as_path()) {
+            Ok(s) => Ok(Some(s.size.unwrap_or_default())),
+            Err(e) if not_found(&e) => Ok(None),
+            Err(e) => Err(SSHError::from(e))?
+        }
     }
 
-    pub fn delete(&self, path: String) -> std::io::Result<()> {
-        Ok(self.sftp.as_ref().unwrap().unlink(path.as_ref())?)
+    fn list(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        let mut keys = vec![];
+        let mut dirs = vec![self.remote(prefix)];
+        while let Some(dir) = dirs.pop() {
+            let entries = match self.sftp().readdir(&dir) {
+                Ok(e) => e,
+                Err(e) if not_found(&e) => continue,
+                Err(e) => Err(SSHError::from(e))?
+            };
+            for (path, stat) in entries {
+                if stat.is_dir() {
+                    dirs.push(path);
+                } else if let Ok(key) = path.strip_prefix(&self.root) {
+                    keys.push(key.to_str().unwrap().to_string());
+                }
+            }
+        }
+        Ok(keys)
     }
-}
 
-impl Default for SSHClient {
-    fn default() -> Self {
-        Self::new()
+    /// Hash on the server when it has `sha256sum`, so nothing has to be downloaded. Otherwise the
+    /// contents are streamed and hashed here.
+    fn hash_all(&self, keys: &[String]) -> Result<Option<String>, StorageError> {
+        if self.remote_hash.load(Ordering::Relaxed) && !keys.is_empty() {
+            let mut paths = vec![];
+            for key in keys {
+                if self.stat(key)?.is_none() {
+                    return Ok(None)
+                }
+                paths.push(self.remote(key));
+            }
+            if let Some(hash) = self.exec_hash(&paths) {
+                return Ok(Some(hash))
+            }
+        }
+        stream_hash(self, keys)
     }
 }
 
 impl Debug for SSHClient {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "SSHClient {{ session: Session {{ blocking: {:?} }} }}", self.session.is_blocking())
+        write!(f, "SSHClient {{ session: Session {{ blocking: {:?} }}, root: {:?} }}", self.session.is_blocking(), self.root)
     }
 }
@@ -0,0 +1,93 @@
+use std::fmt::{Debug, Formatter};
+use std::io::{Read, Write};
+use std::sync::{Condvar, Mutex, PoisonError};
+use futures::future::try_join_all;
+use super::SSHClient;
+use crate::storage::{Storage, StorageError};
+
+/// Several sessions to the same server, so transfers run side by side instead of waiting for
+/// each other on a single connection. Every operation borrows a free session for its duration.
+pub struct SSHPool {
+    clients: Vec<SSHClient>,
+    /// Indexes of the sessions not in use.
+    free: Mutex<Vec<usize>>,
+    released: Condvar
+}
+
+impl SSHPool {
+    /// Open `size` sessions, at least one, storing files under the remote directory `root`.
+    pub async fn connect(size: usize, root: String, username: String, port: u16, host: String) -> std::io::Result<Self> {
+        let clients = try_join_all((0..size.max(1))
+            .map(|_| SSHClient::connect(root.clone(), username.clone(), port, host.clone()))
+        ).await?;
+        Ok(Self {
+            free: Mutex::new((0..clients.len()).rev().collect()),
+            released: Condvar::new(),
+            clients
+        })
+    }
+
+    /// Run `f` on a free session, waiting for one if they're all busy.
+    fn with<T>(&self, f: impl FnOnce(&SSHClient) -> T) -> T {
+        let i = {
+            let mut free = self.free.lock().unwrap();
+            loop {
+                match free.pop() {
+                    Some(i) => break i,
+                    None => free = self.released.wait(free).unwrap()
+                }
+            }
+        };
+        let _borrowed = Borrowed { pool: self, i };
+        f(&self.clients[i])
+    }
+}
+
+/// A session taken from the pool, given back when dropped, even if the operation panics.
+struct Borrowed<'a> {
+    pool: &'a SSHPool,
+    i: usize
+}
+
+impl Drop for Borrowed<'_> {
+    fn drop(&mut self) {
+        self.pool.free.lock().unwrap_or_else(PoisonError::into_inner).push(self.i);
+        self.pool.released.notify_one();
+    }
+}
+
+impl Storage for SSHPool {
+    fn append(&self, key: &str, reader: &mut dyn Read) -> Result<u64, StorageError> {
+        self.with(|c| c.append(key, reader))
+    }
+
+    fn get(&self, key: &str, writer: &mut dyn Write) -> Result<u64, StorageError> {
+        self.with(|c| c.get(key, writer))
+    }
+
+    fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.with(|c| c.delete(key))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), StorageError> {
+        self.with(|c| c.rename(from, to))
+    }
+
+    fn stat(&self, key: &str) -> Result<Option<u64>, StorageError> {
+        self.with(|c| c.stat(key))
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        self.with(|c| c.list(prefix))
+    }
+
+    fn hash_all(&self, keys: &[String]) -> Result<Option<String>, StorageError> {
+        self.with(|c| c.hash_all(keys))
+    }
+}
+
+impl Debug for SSHPool {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SSHPool {{ sessions: {}, clients: {:?} }}", self.clients.len(), self.clients.first())
+    }
+}
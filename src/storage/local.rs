@@ -0,0 +1,154 @@
+use std::fs::{File as StdFile, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::path::PathBuf;
+use walkdir::WalkDir;
+use super::{Storage, StorageError};
+
+/// Storage on a directory of this machine, like a mounted drive.
+#[derive(Debug)]
+pub struct LocalStorage {
+    root: PathBuf
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+fn not_found(e: std::io::Error, key: &str) -> StorageError {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => StorageError::NotFound(key.to_string()),
+        _ => StorageError::IO(e)
+    }
+}
+
+impl Storage for LocalStorage {
+    fn append(&self, key: &str, reader: &mut dyn Read) -> Result<u64, StorageError> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = BufWriter::new(OpenOptions::new().create(true).append(true).open(path)?);
+        let size = std::io::copy(reader, &mut file)?;
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(size)
+    }
+
+    fn get(&self, key: &str, writer: &mut dyn Write) -> Result<u64, StorageError> {
+        let mut file = StdFile::open(self.path(key)).map_err(|e| not_found(e, key))?;
+        Ok(std::io::copy(&mut file, writer)?)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), StorageError> {
+        std::fs::remove_file(self.path(key)).map_err(|e| not_found(e, key))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), StorageError> {
+        let dst = self.path(to);
+        if let Some(parent) = dst.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::rename(self.path(from), dst).map_err(|e| not_found(e, from))
+    }
+
+    fn stat(&self, key: &str) -> Result<Option<u64>, StorageError> {
+        match std::fs::metadata(self.path(key)) {
+            Ok(m) if m.is_file() => Ok(Some(m.len())),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into())
+        }
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        let mut keys = vec![];
+        for entry in WalkDir::new(self.path(prefix)) {
+            let entry = match entry {
+                Ok(e) => e,
+                Err(e) if e.io_error().map(|e| e.kind()) == Some(std::io::ErrorKind::NotFound) => break,
+                Err(e) => return Err(StorageError::IO(e.into()))
+            };
+            if entry.file_type().is_file() {
+                if let Ok(key) = entry.path().strip_prefix(&self.root) {
+                    keys.push(key.to_str().unwrap().to_string());
+                }
+            }
+        }
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::blob_key;
+    use crate::testutil::TempDir;
+
+    /// A storage on a fresh directory, removed with the returned guard.
+    fn storage() -> (TempDir, LocalStorage) {
+        let dir = TempDir::new();
+        let storage = LocalStorage::new(dir.join("store"));
+        (dir, storage)
+    }
+
+    fn read(s: &LocalStorage, key: &str) -> Vec<u8> {
+        let mut data = vec![];
+        s.get(key, &mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn append_and_get() {
+        let (_dir, s) = storage();
+        assert_eq!(s.append("a/b", &mut &b"hello "[..]).unwrap(), 6);
+        assert_eq!(s.append("a/b", &mut &b"world"[..]).unwrap(), 5);
+        assert_eq!(read(&s, "a/b"), b"hello world");
+        assert_eq!(s.stat("a/b").unwrap(), Some(11));
+    }
+
+    #[test]
+    fn missing_keys() {
+        let (_dir, s) = storage();
+        assert_eq!(s.stat("nothing").unwrap(), None);
+        assert!(matches!(s.get("nothing", &mut vec![]), Err(StorageError::NotFound(k)) if k == "nothing"));
+        assert!(matches!(s.delete("nothing"), Err(StorageError::NotFound(_))));
+        assert!(matches!(s.rename("nothing", "else"), Err(StorageError::NotFound(_))));
+        assert_eq!(s.list("").unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn rename_and_delete() {
+        let (_dir, s) = storage();
+        s.append("partial/x", &mut &b"content"[..]).unwrap();
+        s.rename("partial/x", &blob_key("x1")).unwrap();
+        assert_eq!(s.stat("partial/x").unwrap(), None);
+        assert_eq!(read(&s, &blob_key("x1")), b"content");
+        s.delete(&blob_key("x1")).unwrap();
+        assert_eq!(s.stat(&blob_key("x1")).unwrap(), None);
+    }
+
+    #[test]
+    fn list_and_hash() {
+        let (_dir, s) = storage();
+        s.append("blobs/aa/one", &mut &b"one"[..]).unwrap();
+        s.append("blobs/bb/two", &mut &b"two"[..]).unwrap();
+        s.append("other", &mut &b""[..]).unwrap();
+        let mut keys = s.list("blobs").unwrap();
+        keys.sort();
+        assert_eq!(keys, ["blobs/aa/one", "blobs/bb/two"]);
+        assert_eq!(s.list("").unwrap().len(), 3);
+        assert_eq!(
+            s.hash("other").unwrap().as_deref(),
+            Some("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+        );
+        let joined = s.hash_all(&["blobs/aa/one".to_string(), "blobs/bb/two".to_string()]).unwrap();
+        s.append("onetwo", &mut &b"onetwo"[..]).unwrap();
+        assert_eq!(joined, s.hash("onetwo").unwrap());
+        assert_eq!(s.hash_all(&["blobs/aa/one".to_string(), "gone".to_string()]).unwrap(), None);
+    }
+}
@@ -0,0 +1,112 @@
+mod local;
+
+pub use local::LocalStorage;
+
+use std::fmt::Debug;
+use std::io::{Read, Write};
+use hex::ToHex;
+use crate::fs::HashWriter;
+use crate::conf::PathError;
+use crate::crypto::CryptoError;
+use crate::ssh::SSHError;
+
+/// A place where file contents are kept. Contents are addressed by keys, `/` separated paths
+/// relative to the root of the storage. A storage is shared by the concurrent transfers.
+pub trait Storage: Debug + Send + Sync {
+    /// Add everything read from `reader` at the end of `key`, which is created if missing, and
+    /// flush it to disk. Returns the number of bytes added.
+    fn append(&self, key: &str, reader: &mut dyn Read) -> Result<u64, StorageError>;
+
+    /// Write the content stored under `key` into `writer`. Returns the number of bytes read.
+    fn get(&self, key: &str, writer: &mut dyn Write) -> Result<u64, StorageError>;
+
+    fn delete(&self, key: &str) -> Result<(), StorageError>;
+
+    /// Move `from` to `to`, replacing what was there.
+    fn rename(&self, from: &str, to: &str) -> Result<(), StorageError>;
+
+    /// Size of the content under `key`, `None` if there is nothing.
+    fn stat(&self, key: &str) -> Result<Option<u64>, StorageError>;
+
+    /// Every key under `prefix`, recursively.
+    fn list(&self, prefix: &str) -> Result<Vec<String>, StorageError>;
+
+    /// Hex encoded SHA-256 of the content under `key`, `None` if there is nothing.
+    fn hash(&self, key: &str) -> Result<Option<String>, StorageError> {
+        self.hash_all(&[key.to_string()])
+    }
+
+    /// Hex encoded SHA-256 of the contents under `keys` joined in order, `None` if one of them is
+    /// missing. By default the contents are read back and hashed here.
+    fn hash_all(&self, keys: &[String]) -> Result<Option<String>, StorageError> {
+        stream_hash(self, keys)
+    }
+}
+
+/// Hash the contents under `keys` by reading them back through `storage`, what
+/// [`Storage::hash_all`] does when the storage can't hash on its side.
+pub fn stream_hash<S: Storage + ?Sized>(storage: &S, keys: &[String]) -> Result<Option<String>, StorageError> {
+    let mut hasher = HashWriter::new();
+    for key in keys {
+        match storage.get(key, &mut hasher) {
+            Ok(_) => {},
+            Err(StorageError::NotFound(_)) => return Ok(None),
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(Some(hasher.finish().encode_hex()))
+}
+
+/// Key where the blob `hash` is written until it's complete, an interrupted upload resumes from
+/// there.
+pub fn partial_key(hash: &str) -> String {
+    format!("partial/{}", hash)
+}
+
+/// Key of the blob holding the content with SHA-256 `hash`. Blobs are spread over subdirectories
+/// named after the first byte of the hash, so no directory grows too large.
+pub fn blob_key(hash: &str) -> String {
+    let dir: String = hash.chars().take(2).collect();
+    format!("blobs/{}/{}", dir, hash)
+}
+
+/// Whether `name` can be the name of a blob: a hex encoded SHA-256.
+pub fn is_blob_name(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    NotFound(String),
+    /// The content written under the key, here included, isn't all there.
+    Incomplete(String),
+    Path(PathError),
+    SSH(SSHError),
+    /// Stored content that can't be decrypted.
+    Crypto(CryptoError),
+    IO(std::io::Error)
+}
+
+impl From<CryptoError> for StorageError {
+    fn from(value: CryptoError) -> Self {
+        Self::Crypto(value)
+    }
+}
+
+impl From<SSHError> for StorageError {
+    fn from(value: SSHError) -> Self {
+        Self::SSH(value)
+    }
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(value: std::io::Error) -> Self {
+        Self::IO(value)
+    }
+}
+
+impl From<PathError> for StorageError {
+    fn from(value: PathError) -> Self {
+        Self::Path(value)
+    }
+}